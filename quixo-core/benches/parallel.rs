@@ -0,0 +1,31 @@
+//! Compares the parallel modes of MCTS on the same number of iterations:
+//! leaf-parallel rollouts, threads sharing one tree with virtual loss, and
+//! independent trees merged at the end.
+//!
+//! Run with `cargo bench -p quixo-core`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use quixo_core::game::Rules;
+use quixo_core::mcts::{mcts, GameState, MctsConfig, Parallelism};
+
+fn parallel_modes(c: &mut Criterion) {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let modes = [
+        ("leaf", Parallelism::Leaf),
+        ("tree", Parallelism::Tree { threads, virtual_loss: 3 }),
+        ("root", Parallelism::Root { threads }),
+    ];
+    let root: GameState = "XXO2/O3X/X3O/1O3/OX2X O".parse().unwrap();
+    for rollouts_per_leaf in [1, 32] {
+        let mut group = c.benchmark_group(format!("mcts-400x{}", rollouts_per_leaf));
+        group.sample_size(10);
+        for (name, parallelism) in modes {
+            let config = MctsConfig { rollouts_per_leaf, parallelism, ..MctsConfig::default() };
+            group.bench_function(name, |b| b.iter(|| mcts(root, 400, Rules::default(), config, None, None)));
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, parallel_modes);
+criterion_main!(benches);
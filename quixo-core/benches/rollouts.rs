@@ -0,0 +1,24 @@
+//! Measures the pieces of a rollout on the packed bitboard: move
+//! generation, win detection, and whole random games.
+//!
+//! Run with `cargo bench -p quixo-core --bench rollouts`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use quixo_core::bitboard::{random_game, Bitboard, SideBitboard};
+use quixo_core::game::{Player, Rules};
+use quixo_core::position::parse_board;
+
+fn rollouts(c: &mut Criterion) {
+    let rules = Rules::default();
+    let midgame: Bitboard = Bitboard::from(parse_board("XXO2/O3X/X3O/1O3/OX2X").unwrap());
+    let mut group = c.benchmark_group("rollouts");
+    group.bench_function("available_moves", |b| b.iter(|| black_box(midgame).available_moves(Player::O)));
+    group.bench_function("nth_move", |b| b.iter(|| black_box(midgame).nth_move(Player::O, black_box(20))));
+    group.bench_function("has_line", |b| b.iter(|| black_box(midgame).has_line(Player::X, black_box(rules))));
+    group.bench_function("random_game-empty", |b| b.iter(|| random_game(Bitboard::<5>::empty(), Player::X, rules)));
+    group.bench_function("random_game-midgame", |b| b.iter(|| random_game(black_box(midgame), Player::O, rules)));
+    group.finish();
+}
+
+criterion_group!(benches, rollouts);
+criterion_main!(benches);
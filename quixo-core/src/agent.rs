@@ -0,0 +1,252 @@
+//! Players that can be swapped for one another: the UI, tournaments and
+//! bindings only deal with `Agent`s, whatever engine chooses their moves.
+
+use std::sync::mpsc;
+use std::time::Duration;
+use rand::Rng;
+use crate::cancel::CancelToken;
+use crate::eval::{Evaluator, LineEvaluator};
+use crate::game::{History, Move, Rules};
+use crate::mcts::{GameState, Mcts, MctsConfig, SearchState, TimeBudget};
+use crate::negamax::{negamax, SearchLimits};
+
+/// Receives the number of iterations done by a search and its best move so
+/// far.
+pub type Progress = mpsc::Sender<(u32, Option<Move>)>;
+
+/// A player of the game of states S.
+pub trait Agent<S: SearchState = GameState>: Send {
+    /// Short description of the player, e.g. for tournament tables.
+    fn name(&self) -> String;
+
+    /// The move to play in the state reached after the history of the
+    /// game, under the given rules, None if there is none.
+    fn choose_move(&mut self, state: &S, history: &History, rules: Rules) -> Option<Move>;
+
+    /// Report the progress of the next searches to the channel. Agents
+    /// without a notion of progress ignore it.
+    fn set_progress(&mut self, _progress: Option<Progress>) {}
+
+    /// Stop the next searches early when the token is cancelled, playing
+    /// the best move found so far. Agents that answer at once ignore it.
+    fn set_cancel(&mut self, _cancel: Option<CancelToken>) {}
+}
+
+/// Plays one of the available moves uniformly at random.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RandomAgent;
+
+impl<S: SearchState> Agent<S> for RandomAgent {
+    fn name(&self) -> String {
+        String::from("random")
+    }
+
+    fn choose_move(&mut self, state: &S, _history: &History, _rules: Rules) -> Option<Move> {
+        let (moves, n) = state.available_moves();
+        (n > 0).then(|| moves[rand::rng().random_range(0..n)])
+    }
+}
+
+/// Plays the move found by MCTS. The search is kept from one move to the
+/// next, so that the statistics of the states still reachable are reused.
+#[derive(Debug)]
+pub struct MctsAgent<S: SearchState = GameState> {
+    pub iterations: u32,
+    /// Time per move, searched instead of a number of iterations if set.
+    pub time: Option<Duration>,
+    pub config: MctsConfig,
+    progress: Option<Progress>,
+    cancel: Option<CancelToken>,
+    search: Option<Mcts<S>>,
+}
+
+impl<S: SearchState> MctsAgent<S> {
+    pub fn new(iterations: u32, config: MctsConfig) -> Self {
+        MctsAgent { iterations, time: None, config, progress: None, cancel: None, search: None }
+    }
+
+    /// An agent searching for the given time on each move.
+    pub fn timed(time: Duration, config: MctsConfig) -> Self {
+        MctsAgent { time: Some(time), ..Self::new(0, config) }
+    }
+
+    /// The search of the last move, if any.
+    pub fn search(&self) -> Option<&Mcts<S>> {
+        self.search.as_ref()
+    }
+}
+
+impl<S: SearchState> Agent<S> for MctsAgent<S> {
+    fn name(&self) -> String {
+        match self.time {
+            Some(time) => format!("mcts-{}msx{}", time.as_millis(), self.config.rollouts_per_leaf),
+            None => format!("mcts-{}x{}", self.iterations, self.config.rollouts_per_leaf),
+        }
+    }
+
+    fn choose_move(&mut self, state: &S, history: &History, rules: Rules) -> Option<Move> {
+        let search = match &mut self.search {
+            Some(search) if search.rules() == rules && search.config() == &self.config => {
+                search.set_root(*state, history.clone());
+                search
+            }
+            _ => self.search.insert(Mcts::with_history(*state, history.clone(), rules, self.config)),
+        };
+        match self.time {
+            Some(time) => search.search_timed(TimeBudget::from_now(time), self.progress.clone(), self.cancel.as_ref()),
+            None => search.search(self.iterations, self.progress.clone(), self.cancel.as_ref()),
+        }
+    }
+
+    fn set_progress(&mut self, progress: Option<Progress>) {
+        self.progress = progress;
+    }
+
+    fn set_cancel(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+}
+
+/// Plays the move found by `negamax` with the evaluator E.
+#[derive(Debug, Clone)]
+pub struct NegamaxAgent<E = LineEvaluator> {
+    pub limits: SearchLimits,
+    pub evaluator: E,
+    cancel: Option<CancelToken>,
+}
+
+impl<const N: usize> NegamaxAgent<LineEvaluator<N>> {
+    /// An agent searching within the limits with the default heuristic.
+    pub fn new(limits: SearchLimits, rules: Rules) -> Self {
+        NegamaxAgent { limits, evaluator: LineEvaluator::new(rules), cancel: None }
+    }
+}
+
+impl<const N: usize, E: Evaluator<N> + Clone + Send> Agent<GameState<N>> for NegamaxAgent<E> {
+    fn name(&self) -> String {
+        match (self.limits.depth, self.limits.time) {
+            (Some(depth), _) => format!("negamax-d{}", depth),
+            (None, Some(time)) => format!("negamax-{}ms", time.as_millis()),
+            (None, None) => String::from("negamax"),
+        }
+    }
+
+    fn choose_move(&mut self, state: &GameState<N>, history: &History, rules: Rules) -> Option<Move> {
+        negamax(state, history, self.limits, rules, self.evaluator.clone(), self.cancel.as_ref()).best_move
+    }
+
+    fn set_cancel(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Player;
+    use crate::team::TeamState;
+    use crate::testing::{config, state, MIDGAME};
+
+    /// Play the agents against each other from the state, X first, and
+    /// return the winner.
+    fn play<S: SearchState>(mut state: S, agents: &mut [Box<dyn Agent<S>>; 2], rules: Rules) -> Option<Player> {
+        let mut history = History::new();
+        for _ in 0..200 {
+            if let Some(p) = state.winner(rules) {
+                return Some(p);
+            }
+            let agent = &mut agents[state.side() as usize];
+            let m = agent.choose_move(&state, &history, rules)?;
+            history.push(state.key());
+            state = state.play(m).unwrap();
+        }
+        None
+    }
+
+    #[test]
+    fn test_agents_win() {
+        let root = state(MIDGAME);
+        let rules = Rules::default();
+        let mut agents: Vec<Box<dyn Agent>> = vec![
+            Box::new(MctsAgent::<GameState>::new(100, MctsConfig::default())),
+            Box::new(NegamaxAgent::new(SearchLimits::depth(2), rules)),
+        ];
+        for agent in &mut agents {
+            let m = agent.choose_move(&root, &History::new(), rules).unwrap();
+            assert_eq!(root.play(m).unwrap().winner(rules), Some(Player::X), "{}", agent.name());
+        }
+        let m = RandomAgent.choose_move(&root, &History::new(), rules).unwrap();
+        assert!(root.play(m).is_ok());
+    }
+
+    #[test]
+    fn test_swap_agents() {
+        let rules = Rules::default();
+        let mut agents: [Box<dyn Agent>; 2] = [Box::new(NegamaxAgent::new(SearchLimits::depth(2), rules)), Box::new(RandomAgent)];
+        assert_eq!(agents[0].name(), "negamax-d2");
+        let winner = play("5/5/5/5/5 X".parse::<GameState>().unwrap(), &mut agents, rules);
+        assert_eq!(winner, Some(Player::X));
+        let mut agents: [Box<dyn Agent<TeamState>>; 2] = [Box::new(MctsAgent::<TeamState>::new(100, config(10, true))), Box::new(RandomAgent)];
+        let root: TeamState = "XxXx1/O4/o4/O4/5 X1".parse().unwrap();
+        assert_eq!(play(root, &mut agents, rules), Some(Player::X));
+    }
+
+    #[test]
+    fn test_progress() {
+        let (tx, rx) = mpsc::channel();
+        let mut agent = MctsAgent::new(20, config(10, false));
+        assert_eq!(Agent::<GameState>::name(&agent), "mcts-20x10");
+        agent.set_progress(Some(tx));
+        let m = agent.choose_move(&state(MIDGAME), &History::new(), Rules::default());
+        let reports: Vec<_> = rx.try_iter().collect();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|&(_, best)| best.is_some()) && m.is_some());
+    }
+
+    #[test]
+    fn test_timed_mcts_agent() {
+        let root = state(MIDGAME);
+        let mut agent = MctsAgent::timed(Duration::from_millis(100), config(10, true));
+        assert_eq!(Agent::<GameState>::name(&agent), "mcts-100msx10");
+        let m = agent.choose_move(&root, &History::new(), Rules::default()).unwrap();
+        assert!(root.play(m).is_ok());
+        assert!(agent.search().unwrap().node(&root).unwrap().visits > 0);
+    }
+
+    #[test]
+    fn test_cancel_agents() {
+        let root: GameState = "5/5/5/5/5 X".parse().unwrap();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let mut agents: Vec<Box<dyn Agent>> = vec![
+            Box::new(MctsAgent::<GameState>::timed(Duration::from_secs(60), config(10, true))),
+            Box::new(NegamaxAgent::new(SearchLimits::time(Duration::from_secs(60)), Rules::default())),
+        ];
+        let start = std::time::Instant::now();
+        for agent in &mut agents {
+            agent.set_cancel(Some(cancel.clone()));
+            let m = agent.choose_move(&root, &History::new(), Rules::default()).unwrap();
+            assert!(root.play(m).is_ok(), "{}", agent.name());
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_mcts_agent_reuses_search() {
+        let rules = Rules::default();
+        let mut agent = MctsAgent::new(50, config(10, true));
+        let root: GameState = "5/5/5/5/5 X".parse().unwrap();
+        let m = agent.choose_move(&root, &History::new(), rules).unwrap();
+        let mut history = History::new();
+        history.push(root.key());
+        let reply = RandomAgent.choose_move(&root.play(m).unwrap(), &history, rules).unwrap();
+        history.push(root.play(m).unwrap().key());
+        let next = root.play(m).unwrap().play(reply).unwrap();
+        let visits = agent.search().unwrap().node(&next).map_or(0, |n| n.visits);
+        agent.choose_move(&next, &history, rules).unwrap();
+        let search = agent.search().unwrap();
+        assert_eq!(search.root(), &next);
+        assert_eq!(search.history(), &history);
+        assert_eq!(search.node(&next).unwrap().visits, visits + 500);
+    }
+}
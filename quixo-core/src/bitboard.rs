@@ -0,0 +1,576 @@
+use rand::Rng;
+use crate::game::{allowed_moves, num_allowed_moves, Board, GameError, Move, Player, Rules, Shift, Side, MAX_MOVES, MAX_SIZE};
+
+/// Mask of the bit used for the cell in column x and row y of a board of
+/// size N.
+pub const fn cell_mask<const N: usize>(x: u8, y: u8) -> u64 {
+    1 << (y as u32 * N as u32 + x as u32)
+}
+
+/// A board packed into one mask per player.
+/// Bit `N * y + x` is set in `x` (resp. `o`) if the cell in column x and
+/// row y belongs to player X (resp. O).
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bitboard<const N: usize = 5> {
+    pub x: u64,
+    pub o: u64,
+}
+
+impl<const N: usize> Bitboard<N> {
+    /// All the cells of the board.
+    pub const FULL: u64 = u64::MAX >> (64 - N * N);
+    const ROW: u64 = (1 << N) - 1;
+    const COLUMN: u64 = {
+        let mut column = 0;
+        let mut i = 0;
+        while i < N {
+            column |= 1 << (N * i);
+            i += 1;
+        }
+        column
+    };
+    /// The border cells, the only ones that can be picked up.
+    pub const EDGE: u64 = {
+        let inner_row = Self::ROW & !1 & !(1 << (N - 1));
+        let mut inner = 0;
+        let mut i = 1;
+        while i < N - 1 {
+            inner |= inner_row << (N * i);
+            i += 1;
+        }
+        Self::FULL & !inner
+    };
+    /// The corners, from which a tile can only be pushed in 2 directions.
+    const CORNERS: u64 = cell_mask::<N>(0, 0)
+        | cell_mask::<N>(N as u8 - 1, 0)
+        | cell_mask::<N>(0, N as u8 - 1)
+        | cell_mask::<N>(N as u8 - 1, N as u8 - 1);
+    /// For each cell, the moves picking up its tile in `allowed_moves`
+    /// order, and their number.
+    const CELL_MOVES: [([Move; 3], usize); 64] = {
+        let all = allowed_moves::<N>();
+        let mut cell_moves = [([all[0]; 3], 0); 64];
+        let mut i = 0;
+        while i < num_allowed_moves(N) {
+            let m = all[i];
+            let cell = &mut cell_moves[m.y as usize * N + m.x as usize];
+            cell.0[cell.1] = m;
+            cell.1 += 1;
+            i += 1;
+        }
+        cell_moves
+    };
+    /// `line_starts(k)` for every line length k.
+    const LINE_STARTS: [[(usize, u64); 4]; MAX_SIZE + 1] = {
+        let mut starts = [[(1, 0); 4]; MAX_SIZE + 1];
+        let mut k = 1;
+        while k <= N {
+            starts[k] = Self::line_starts(k);
+            k += 1;
+        }
+        starts
+    };
+    /// The lines across the whole board: rows, columns, then the two
+    /// diagonals, followed by unused entries.
+    const FULL_LINES: [u64; 2 * MAX_SIZE + 2] = {
+        let mut lines = [0; 2 * MAX_SIZE + 2];
+        let mut i = 0;
+        while i < N {
+            lines[i] = Self::ROW << (N * i);
+            lines[N + i] = Self::COLUMN << i;
+            lines[2 * N] |= 1 << ((N + 1) * i);
+            lines[2 * N + 1] |= 1 << ((N - 1) * (i + 1));
+            i += 1;
+        }
+        lines
+    };
+
+    /// Cells of the columns `from` to `to`, both included.
+    const fn columns(from: usize, to: usize) -> u64 {
+        Self::COLUMN * (((1 << (to + 1)) - 1) & !((1 << from) - 1))
+    }
+
+    /// Cells of the rows `from` to `to`, both included.
+    const fn rows(from: usize, to: usize) -> u64 {
+        (u64::MAX >> (64 - N * (to + 1))) & !((1 << (N * from)) - 1)
+    }
+
+    /// For rows, columns, diagonals and anti-diagonals in this order, the
+    /// distance between the bits of consecutive cells of a line, and the
+    /// cells where a line of k tiles can start.
+    const fn line_starts(k: usize) -> [(usize, u64); 4] {
+        if k == 0 || k > N {
+            return [(1, 0); 4];
+        }
+        let left = Self::columns(0, N - k);
+        let right = Self::columns(k - 1, N - 1);
+        let top = Self::rows(0, N - k);
+        [(1, left), (N, top), (N + 1, left & top), (N - 1, right & top)]
+    }
+
+    /// Masks of the winning lines under the given rules: rows, then
+    /// columns, then diagonals if they count.
+    pub fn lines(rules: Rules) -> Vec<u64> {
+        let k = rules.win_length(N);
+        let directions = if rules.diagonals { 4 } else { 2 };
+        let mut lines = Vec::new();
+        let Some(&line_starts) = Self::LINE_STARTS.get(k) else {
+            return lines;
+        };
+        for (step, starts) in line_starts.into_iter().take(directions) {
+            for start in (0..N * N).filter(|&i| starts & (1 << i) != 0) {
+                lines.push((0..k).fold(0, |line, i| line | 1 << (start + step * i)));
+            }
+        }
+        lines
+    }
+
+    pub const fn empty() -> Self {
+        Bitboard { x: 0, o: 0 }
+    }
+
+    /// Cells owned by player p.
+    pub fn mask(&self, p: Player) -> u64 {
+        match p {
+            Player::X => self.x,
+            Player::O => self.o,
+        }
+    }
+
+    fn mask_mut(&mut self, p: Player) -> &mut u64 {
+        match p {
+            Player::X => &mut self.x,
+            Player::O => &mut self.o,
+        }
+    }
+
+    pub fn get(&self, x: u8, y: u8) -> Option<Player> {
+        let m = cell_mask::<N>(x, y);
+        if self.x & m != 0 {
+            Some(Player::X)
+        } else if self.o & m != 0 {
+            Some(Player::O)
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, x: u8, y: u8, cell: Option<Player>) {
+        let m = cell_mask::<N>(x, y);
+        self.x &= !m;
+        self.o &= !m;
+        if let Some(p) = cell {
+            *self.mask_mut(p) |= m;
+        }
+    }
+
+    /// Number of tiles on the board.
+    pub fn count(&self) -> u32 {
+        (self.x | self.o).count_ones()
+    }
+
+    /// Apply the move m for player p, with the same rules as `Move::apply`.
+    pub fn apply(&self, m: Move, p: Player) -> Result<Bitboard<N>, GameError> {
+        m.validate_with::<N>(|x, y| self.get(x, y) != Some(p.next()))?;
+        Ok(self.apply_unchecked(m, p))
+    }
+
+    /// The cells of `mask` after the valid move m, leaving the destination
+    /// cell on the edge empty, and the mask of the destination cell.
+    pub(crate) fn slide(mask: u64, m: Move) -> (u64, u64) {
+        let cell = cell_mask::<N>(m.x, m.y);
+        let before = cell - 1;
+        let after = !(before | cell);
+        let row = Self::ROW << (N * m.y as usize);
+        let column = Self::COLUMN << m.x;
+        let last = N as u8 - 1;
+        // the tiles between the picked one and the destination edge slide
+        // by one cell towards the picked tile's position
+        let (segment, destination) = match m.shift {
+            Shift::LEFT => (row & before, cell_mask::<N>(0, m.y)),
+            Shift::RIGHT => (row & after, cell_mask::<N>(last, m.y)),
+            Shift::TOP => (column & before, cell_mask::<N>(m.x, 0)),
+            Shift::BOTTOM => (column & after, cell_mask::<N>(m.x, last)),
+        };
+        let moved = mask & segment;
+        let moved = match m.shift {
+            Shift::LEFT => moved << 1,
+            Shift::RIGHT => moved >> 1,
+            Shift::TOP => moved << N,
+            Shift::BOTTOM => moved >> N,
+        };
+        ((mask & !(segment | cell)) | moved, destination)
+    }
+
+    /// Whether player p owns a winning line under the given rules.
+    pub fn has_line(&self, p: Player, rules: Rules) -> bool {
+        let mask = self.mask(p);
+        let k = rules.win_length(N);
+        if k == N {
+            let num_lines = if rules.diagonals { 2 * N + 2 } else { 2 * N };
+            return Self::FULL_LINES[..num_lines].iter().any(|&line| line & !mask == 0);
+        }
+        let directions = if rules.diagonals { 4 } else { 2 };
+        let Some(line_starts) = Self::LINE_STARTS.get(k) else {
+            return false;
+        };
+        line_starts[..directions].iter().any(|&(step, starts)| {
+            // cells followed by k - 1 tiles of p in the direction of the line
+            let aligned = (1..k).fold(mask, |aligned, i| aligned & (mask >> (step * i)));
+            aligned & starts != 0
+        })
+    }
+
+    /// Winner of the position reached right after `mover` played, following
+    /// the official rule: completing a line of the opponent loses, even if
+    /// the mover completes one of their own lines at the same time.
+    /// In the misère variant, the owner of a line loses instead, and a move
+    /// completing lines of both players loses for the mover.
+    pub fn winner_after_move(&self, mover: Player, rules: Rules) -> Option<Player> {
+        let opponent = mover.next();
+        let (mover_line, opponent_line) = (self.has_line(mover, rules), self.has_line(opponent, rules));
+        if rules.misere {
+            if mover_line {
+                Some(opponent)
+            } else if opponent_line {
+                Some(mover)
+            } else {
+                None
+            }
+        } else if opponent_line {
+            Some(opponent)
+        } else if mover_line {
+            Some(mover)
+        } else {
+            None
+        }
+    }
+
+    /// Same as `game::winner`: the winner if one of the players has a line,
+    /// X being checked first.
+    pub fn winner(&self, rules: Rules) -> Option<Player> {
+        let owner = [Player::X, Player::O].into_iter().find(|&p| self.has_line(p, rules));
+        if rules.misere {
+            owner.map(|p| p.next())
+        } else {
+            owner
+        }
+    }
+}
+
+/// A bitboard on which sides move in turn: `Bitboard` for the two players,
+/// `team::TeamBitboard` for the four seats. Move generation and random games
+/// only need the cells a side can pick up and how a move changes the board.
+pub trait SideBitboard<const N: usize>: Copy + Eq {
+    type Side: Side;
+
+    /// Border cells that side s is allowed to pick up.
+    fn movable(&self, s: Self::Side) -> u64;
+
+    /// Apply a move already known to be legal for side s.
+    fn apply_unchecked(&self, m: Move, s: Self::Side) -> Self;
+
+    /// The tiles of the two teams, which make the lines.
+    fn teams(&self) -> Bitboard<N>;
+
+    /// Moves of side s, in `allowed_moves` order.
+    fn available_moves(&self, s: Self::Side) -> ([Move; MAX_MOVES], usize) {
+        let mut available_moves = [Move { x: 0, y: 0, shift: Shift::TOP }; MAX_MOVES];
+        let mut num_available_moves = 0;
+        let mut movable = self.movable(s);
+        while movable != 0 {
+            let (moves, n) = Bitboard::<N>::CELL_MOVES[movable.trailing_zeros() as usize];
+            available_moves[num_available_moves..num_available_moves + n].copy_from_slice(&moves[..n]);
+            num_available_moves += n;
+            movable &= movable - 1;
+        }
+        (available_moves, num_available_moves)
+    }
+
+    /// Number of moves of side s.
+    fn num_moves(&self, s: Self::Side) -> usize {
+        let movable = self.movable(s);
+        (3 * movable.count_ones() - (movable & Bitboard::<N>::CORNERS).count_ones()) as usize
+    }
+
+    /// The move at index i of `available_moves`, found without building the
+    /// whole list.
+    fn nth_move(&self, s: Self::Side, mut i: usize) -> Option<Move> {
+        let mut movable = self.movable(s);
+        while movable != 0 {
+            let (moves, n) = Bitboard::<N>::CELL_MOVES[movable.trailing_zeros() as usize];
+            if i < n {
+                return Some(moves[i]);
+            }
+            i -= n;
+            movable &= movable - 1;
+        }
+        None
+    }
+
+    /// Moves of side s leading to distinct boards, with the boards they
+    /// lead to. Of the moves giving the same board, the first one in
+    /// `allowed_moves` order is kept.
+    fn unique_moves(&self, s: Self::Side) -> ([(Move, Self); MAX_MOVES], usize) {
+        let (available_moves, num_available_moves) = self.available_moves(s);
+        let mut unique_moves = [(available_moves[0], *self); MAX_MOVES];
+        let mut num_unique_moves = 0;
+        for &m in &available_moves[..num_available_moves] {
+            let b = self.apply_unchecked(m, s);
+            if !unique_moves[..num_unique_moves].iter().any(|&(_, u)| u == b) {
+                unique_moves[num_unique_moves] = (m, b);
+                num_unique_moves += 1;
+            }
+        }
+        (unique_moves, num_unique_moves)
+    }
+}
+
+impl<const N: usize> SideBitboard<N> for Bitboard<N> {
+    type Side = Player;
+
+    fn movable(&self, p: Player) -> u64 {
+        Self::EDGE & !self.mask(p.next())
+    }
+
+    fn apply_unchecked(&self, m: Move, p: Player) -> Bitboard<N> {
+        let (x, destination) = Self::slide(self.x, m);
+        let (o, _) = Self::slide(self.o, m);
+        let mut b = Bitboard { x, o };
+        *b.mask_mut(p) |= destination;
+        b
+    }
+
+    fn teams(&self) -> Bitboard<N> {
+        *self
+    }
+}
+
+impl<const N: usize> From<&Board<N>> for Bitboard<N> {
+    fn from(b: &Board<N>) -> Self {
+        let mut bb = Bitboard::<N>::empty();
+        for (y, row) in b.iter().enumerate() {
+            for (x, &cell) in row.iter().enumerate() {
+                bb.set(x as u8, y as u8, cell);
+            }
+        }
+        bb
+    }
+}
+
+impl<const N: usize> From<Board<N>> for Bitboard<N> {
+    fn from(b: Board<N>) -> Self {
+        Bitboard::from(&b)
+    }
+}
+
+impl<const N: usize> From<Bitboard<N>> for Board<N> {
+    fn from(bb: Bitboard<N>) -> Self {
+        let mut b: Board<N> = [[None; N]; N];
+        for (y, row) in b.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                *cell = bb.get(x as u8, y as u8);
+            }
+        }
+        b
+    }
+}
+
+/// Bitboard version of `game::random_game`, used for rollouts: play a random
+/// game from the board b with side s to move, and return the winning team,
+/// None for a draw.
+pub fn random_game<const N: usize, B: SideBitboard<N>>(mut b: B, mut side: B::Side, rules: Rules) -> Option<Player> {
+    let draw_rules = rules.draw;
+    let mut rng = rand::rng();
+    // the starting position was reached by a move of the other team
+    if let Some(winner_team) = b.teams().winner_after_move(side.team().next(), rules) {
+        return Some(winner_team);
+    }
+    // positions reached since a neutral tile was last taken
+    let mut history: Vec<(B, B::Side)> = Vec::new();
+    if draw_rules.repetitions.is_some() {
+        history.push((b, side));
+    }
+    let mut plies = 0;
+    loop {
+        if draw_rules.is_draw(plies, 1) {
+            return None;
+        }
+        let num_moves = b.num_moves(side);
+        if num_moves == 0 {
+            return None;
+        }
+        let mv = b.nth_move(side, rng.random_range(0..num_moves)).expect("the index is below the number of moves");
+        let count = b.teams().count();
+        b = b.apply_unchecked(mv, side);
+        if let Some(winner_team) = b.teams().winner_after_move(side.team(), rules) {
+            return Some(winner_team);
+        }
+        side = side.next();
+        plies += 1;
+        if draw_rules.repetitions.is_some() {
+            if b.teams().count() > count {
+                history.clear();
+            }
+            history.push((b, side));
+            let repetitions = history.iter().filter(|&&h| h == (b, side)).count() as u32;
+            if draw_rules.is_draw(plies, repetitions) {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{random_move, winner_after_move, DrawRules};
+    use crate::position::parse_board;
+    use crate::testing::{state, MIDGAME};
+
+    #[test]
+    fn test_board_round_trip() {
+        let b: Board = *state(MIDGAME).board();
+        let bb = Bitboard::from(b);
+        assert_eq!(bb.get(0, 1), Some(Player::O));
+        assert_eq!(bb.get(1, 2), Some(Player::X));
+        assert_eq!(bb.get(4, 4), None);
+        assert_eq!(Board::from(bb), b);
+    }
+
+    /// Replay random games on boards of size N and compare every legal and
+    /// illegal move.
+    fn check_apply_matches_board<const N: usize>(games: usize) {
+        for _ in 0..games {
+            let mut b: Board<N> = [[None; N]; N];
+            let mut p = Player::X;
+            for _ in 0..40 {
+                let bb = Bitboard::from(b);
+                for y in 0..N as u8 + 1 {
+                    for x in 0..N as u8 + 1 {
+                        for shift in [Shift::TOP, Shift::BOTTOM, Shift::LEFT, Shift::RIGHT] {
+                            let m = Move { x, y, shift };
+                            assert_eq!(m.apply(p, &b).map(Bitboard::from), bb.apply(m, p));
+                        }
+                    }
+                }
+                let rules = Rules::default();
+                assert_eq!(bb.winner(rules), crate::game::winner(&b, rules));
+                assert_eq!(bb.winner_after_move(p.next(), rules), winner_after_move(&b, p.next(), rules));
+                let (moves, n) = bb.available_moves(p);
+                assert_eq!(bb.num_moves(p), n);
+                assert!((0..n).all(|i| bb.nth_move(p, i) == Some(moves[i])));
+                assert_eq!(bb.nth_move(p, n), None);
+                let m = random_move(&b, p).unwrap();
+                b = m.apply(p, &b).unwrap();
+                p = p.next();
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_matches_board() {
+        check_apply_matches_board::<5>(50);
+        check_apply_matches_board::<3>(10);
+        check_apply_matches_board::<4>(10);
+        check_apply_matches_board::<6>(10);
+        check_apply_matches_board::<8>(10);
+    }
+
+    #[test]
+    fn test_masks() {
+        assert_eq!(Bitboard::<5>::FULL, (1 << 25) - 1);
+        assert_eq!(Bitboard::<5>::EDGE.count_ones(), 16);
+        assert_eq!(Bitboard::<8>::FULL, u64::MAX);
+        assert_eq!(Bitboard::<8>::EDGE.count_ones(), 28);
+        assert_eq!(Bitboard::<3>::EDGE, Bitboard::<3>::FULL & !cell_mask::<3>(1, 1));
+        assert_eq!(Bitboard::<4>::lines(Rules::default()).len(), 10);
+        assert!(Bitboard::<6>::lines(Rules::default()).iter().all(|l| l.count_ones() == 6));
+        assert_eq!(Bitboard::<6>::empty().available_moves(Player::X).1, 56);
+        assert_eq!(Bitboard::<6>::empty().num_moves(Player::X), 56);
+        assert_eq!(Bitboard::<3>::lines(Rules::default())[..], Bitboard::<3>::FULL_LINES[..8]);
+        assert_eq!(Bitboard::<8>::lines(Rules::default())[..], Bitboard::<8>::FULL_LINES[..]);
+    }
+
+    #[test]
+    fn test_random_game_move_limit() {
+        // with no plies allowed, only already won positions have a winner
+        let rules = Rules { draw: DrawRules { repetitions: None, max_plies: Some(0) }, ..Rules::default() };
+        assert_eq!(random_game(Bitboard::<5>::empty(), Player::X, rules), None);
+        let bb: Bitboard = Bitboard::from(state(MIDGAME).board());
+        assert_eq!(random_game(bb, Player::X, rules), None);
+        let mut bb = bb;
+        bb.set(1, 4, Some(Player::X));
+        assert_eq!(random_game(bb, Player::O, rules), Some(Player::X));
+    }
+
+    #[test]
+    fn test_winner() {
+        let mut bb: Bitboard = Bitboard::empty();
+        for i in 0..5 {
+            bb.set(i, i, Some(Player::O));
+        }
+        let rules = Rules::default();
+        assert!(bb.has_line(Player::O, rules));
+        assert!(!bb.has_line(Player::X, rules));
+        assert_eq!(bb.winner(rules), Some(Player::O));
+        assert_eq!(bb.winner_after_move(Player::O, rules), Some(Player::O));
+        // completing both lines loses for the mover
+        let mut bb: Bitboard = Bitboard::empty();
+        for i in 0..5 {
+            bb.set(i, 0, Some(Player::O));
+            bb.set(i, 4, Some(Player::X));
+        }
+        assert!(bb.has_line(Player::O, rules) && bb.has_line(Player::X, rules));
+        assert_eq!(bb.winner_after_move(Player::O, rules), Some(Player::X));
+        assert_eq!(bb.winner_after_move(Player::X, rules), Some(Player::O));
+    }
+
+    #[test]
+    fn test_rule_variants() {
+        let bb: Bitboard = Bitboard::from(parse_board("XXXX1/O4/1O3/2O2/5").unwrap());
+        let four = Rules { win_length: Some(4), ..Rules::default() };
+        let no_diagonals = Rules { diagonals: false, ..Rules::default() };
+        let misere = Rules { misere: true, ..four };
+        assert!(!bb.has_line(Player::X, Rules::default()));
+        assert!(bb.has_line(Player::X, four));
+        assert!(!bb.has_line(Player::O, four));
+        assert!(bb.has_line(Player::O, Rules { win_length: Some(3), ..Rules::default() }));
+        assert_eq!(bb.winner_after_move(Player::X, four), Some(Player::X));
+        assert_eq!(bb.winner_after_move(Player::X, misere), Some(Player::O));
+        assert_eq!(bb.winner_after_move(Player::O, misere), Some(Player::O));
+        assert_eq!(bb.winner(misere), Some(Player::O));
+        let mut diagonal: Bitboard = Bitboard::empty();
+        for i in 0..5 {
+            diagonal.set(4 - i, i, Some(Player::X));
+        }
+        assert!(diagonal.has_line(Player::X, Rules::default()));
+        assert!(!diagonal.has_line(Player::X, no_diagonals));
+    }
+
+    #[test]
+    fn test_has_line_matches_lines() {
+        let variants = [
+            Rules::default(),
+            Rules { win_length: Some(3), ..Rules::default() },
+            Rules { win_length: Some(4), diagonals: false, ..Rules::default() },
+        ];
+        assert_eq!(Bitboard::<5>::lines(variants[1]).len(), 3 * 5 * 2 + 9 * 2);
+        for rules in variants {
+            let lines = Bitboard::<5>::lines(rules);
+            for _ in 0..200 {
+                let mut b: Board = [[None; 5]; 5];
+                for _ in 0..30 {
+                    let p = rand::random();
+                    b = random_move(&b, p).unwrap().apply(p, &b).unwrap();
+                }
+                let bb = Bitboard::from(b);
+                for p in [Player::X, Player::O] {
+                    let expected = lines.iter().any(|&l| bb.mask(p) & l == l);
+                    assert_eq!(bb.has_line(p, rules), expected);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Static evaluation of positions, for the searches that stop before the end
+//! of the game: the depth limit of `negamax`, the rollout cutoff of
+//! `mcts_with_cutoff`, and analysis tools.
+
+use rand::Rng;
+use crate::bitboard::Bitboard;
+use crate::game::{find_available_moves, Player, Rules, MAX_SIZE};
+use crate::mcts::GameState;
+use crate::simulations::{parallel_games, Result};
+
+/// Scores states for the player to move: positive when the player to move
+/// is better, negative when the opponent is.
+pub trait Evaluator<const N: usize = 5> {
+    fn evaluate(&self, state: &GameState<N>) -> i32;
+}
+
+/// Any function of the state is an evaluator.
+impl<const N: usize, F: Fn(&GameState<N>) -> i32> Evaluator<N> for F {
+    fn evaluate(&self, state: &GameState<N>) -> i32 {
+        self(state)
+    }
+}
+
+impl<const N: usize> Evaluator<N> for &LineEvaluator<N> {
+    fn evaluate(&self, state: &GameState<N>) -> i32 {
+        (*self).evaluate(state)
+    }
+}
+
+/// Tiles of both players in one of the winning lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCount {
+    /// Mask of the cells of the line, as in `Bitboard::lines`.
+    pub line: u64,
+    pub x: u32,
+    pub o: u32,
+}
+
+impl LineCount {
+    /// Tiles of player p in the line.
+    pub fn tiles(&self, p: Player) -> u32 {
+        match p {
+            Player::X => self.x,
+            Player::O => self.o,
+        }
+    }
+
+    /// Whether both players have tiles in the line.
+    pub fn blocked(&self) -> bool {
+        self.x > 0 && self.o > 0
+    }
+}
+
+/// The default heuristic: every winning line is worth `weights[k]` to the
+/// player owning k of its tiles, divided by `blocked_divisor` when the
+/// opponent has tiles in it too, and the score is the worth of the lines of
+/// the player to move minus the worth of the lines of the opponent. In the
+/// misère variant, the score is reversed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEvaluator<const N: usize = 5> {
+    /// Worth of a line by number of tiles of the player, from 0 to N.
+    pub weights: [i32; MAX_SIZE + 1],
+    pub blocked_divisor: i32,
+    rules: Rules,
+    lines: Vec<u64>,
+}
+
+impl<const N: usize> LineEvaluator<N> {
+    /// The heuristic for the given rules, each tile in a line multiplying
+    /// its worth by 4 and a blocked line being worth 4 times less.
+    pub fn new(rules: Rules) -> Self {
+        let mut weights = [0; MAX_SIZE + 1];
+        for (k, w) in weights.iter_mut().enumerate().skip(1) {
+            *w = 1 << (2 * (k - 1));
+        }
+        LineEvaluator { weights, blocked_divisor: 4, rules, lines: Bitboard::<N>::lines(rules) }
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.rules
+    }
+
+    /// Tiles of the players in each winning line of the state.
+    pub fn line_counts(&self, state: &GameState<N>) -> Vec<LineCount> {
+        let b = Bitboard::from(state.board());
+        self.lines.iter()
+            .map(|&line| LineCount { line, x: (b.x & line).count_ones(), o: (b.o & line).count_ones() })
+            .collect()
+    }
+
+    /// Worth of the lines of player p.
+    fn worth(&self, b: &Bitboard<N>, p: Player) -> i32 {
+        let (own, other) = (b.mask(p), b.mask(p.next()));
+        self.lines.iter().map(|&line| {
+            let w = self.weights[(own & line).count_ones() as usize];
+            if other & line != 0 { w / self.blocked_divisor } else { w }
+        }).sum()
+    }
+}
+
+impl<const N: usize> Evaluator<N> for LineEvaluator<N> {
+    fn evaluate(&self, state: &GameState<N>) -> i32 {
+        let b = Bitboard::from(state.board());
+        let p = state.player();
+        let score = self.worth(&b, p) - self.worth(&b, p.next());
+        if self.rules.misere { -score } else { score }
+    }
+}
+
+/// Rollouts stopped after a number of plies, the evaluator deciding the
+/// winner of the games still going on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RolloutCutoff<E> {
+    pub plies: u32,
+    pub evaluator: E,
+}
+
+impl<E> RolloutCutoff<E> {
+    /// Play a random game from the state for at most `plies` plies, and
+    /// return its winner: the player the evaluator favors if the game is
+    /// still going on, None for a draw or an even evaluation. Draw rules
+    /// are not applied.
+    pub fn game<const N: usize>(&self, mut state: GameState<N>, rules: Rules) -> Option<Player>
+    where
+        E: Evaluator<N>,
+    {
+        let mut rng = rand::rng();
+        for _ in 0..self.plies {
+            if let Some(p) = state.winner(rules) {
+                return Some(p);
+            }
+            let (moves, n) = find_available_moves(state.board(), state.player());
+            if n == 0 {
+                return None;
+            }
+            state = state.play(moves[rng.random_range(0..n)]).expect("available moves are legal");
+        }
+        if let Some(p) = state.winner(rules) {
+            return Some(p);
+        }
+        match self.evaluator.evaluate(&state).signum() {
+            1 => Some(state.player()),
+            -1 => Some(state.player().next()),
+            _ => None,
+        }
+    }
+
+    /// Play n games in parallel with `game`.
+    pub fn rollouts<const N: usize>(&self, state: GameState<N>, n: u32, rules: Rules) -> Result
+    where
+        E: Evaluator<N> + Sync,
+    {
+        parallel_games(n, || self.game(state, rules))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::state;
+
+    #[test]
+    fn test_line_counts() {
+        let eval = LineEvaluator::new(Rules::default());
+        let counts = eval.line_counts(&state("XXXX1/O4/O4/5/5 O"));
+        assert_eq!(counts.len(), 12);
+        // the top row, then the first column
+        assert_eq!(counts[0], LineCount { line: 0b11111, x: 4, o: 0 });
+        assert_eq!(counts[5].tiles(Player::O), 2);
+        assert!(counts[5].blocked());
+        assert_eq!(counts.iter().map(|c| c.x).sum::<u32>(), 4 + 4 + 1);
+    }
+
+    #[test]
+    fn test_line_evaluator() {
+        let eval = LineEvaluator::new(Rules::default());
+        assert_eq!(eval.evaluate(&state("5/5/5/5/5 X")), 0);
+        let s = state("XXXX1/O4/O4/5/5 O");
+        // the four tiles in a row are worth more than the two of O
+        assert!(eval.evaluate(&s) < 0);
+        assert_eq!(eval.evaluate(&s), -eval.evaluate(&state("XXXX1/O4/O4/5/5 X")));
+        let misere = LineEvaluator::new(Rules { misere: true, ..Rules::default() });
+        assert_eq!(misere.evaluate(&s), -eval.evaluate(&s));
+        // closures are evaluators too
+        let zero = |_: &GameState| 0;
+        assert_eq!(zero.evaluate(&s), 0);
+    }
+
+    #[test]
+    fn test_rollout_cutoff() {
+        let cutoff = RolloutCutoff { plies: 0, evaluator: LineEvaluator::new(Rules::default()) };
+        let s = state("XXXX1/O4/O4/5/5 O");
+        assert_eq!(cutoff.game(s, Rules::default()), Some(Player::X));
+        let result = cutoff.rollouts(s, 10, Rules::default());
+        assert_eq!(result.wins_x, 10);
+        let cutoff = RolloutCutoff { plies: 4, ..cutoff };
+        let result = cutoff.rollouts(state("5/5/5/5/5 X"), 100, Rules::default());
+        assert_eq!(result.wins_x + result.wins_o + result.draws, 100);
+    }
+}
@@ -1,7 +1,11 @@
 use std::fmt::Display;
+use std::str::FromStr;
 use rand::{Rng, distr::{Distribution, StandardUniform}};
+use crate::bitboard::{self, Bitboard, SideBitboard};
+use crate::mcts::{GameState, SearchState};
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Player{
     X,
     O
@@ -34,7 +38,27 @@ impl Distribution<Player> for StandardUniform {
     }
 }
 
+/// Who moves in turn: a player of the two-player game, or a seat of the
+/// four-player game (`team::Seat`).
+pub trait Side: Copy + Eq {
+    /// The side playing after this one.
+    fn next(self) -> Self;
+    /// The player whose lines the tiles of the side make.
+    fn team(self) -> Player;
+}
+
+impl Side for Player {
+    fn next(self) -> Player {
+        Player::next(&self)
+    }
+
+    fn team(self) -> Player {
+        self
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Shift {
     TOP,
     BOTTOM,
@@ -53,9 +77,17 @@ impl Display for Shift {
     }
 }
 
-pub type Board = [[Option<Player>; 5]; 5];
+/// Largest supported board size: masks of the cells fit in a `u64` and the
+/// `12 * N - 16` moves of a board fit in the `[Move; MAX_MOVES]` buffers.
+pub const MAX_SIZE: usize = 8;
+/// Largest number of moves a player can have, on the largest board.
+pub const MAX_MOVES: usize = 12 * MAX_SIZE - 16;
+
+/// A board of N rows and N columns, 5 by default as in the original game.
+pub type Board<const N: usize = 5> = [[Option<Player>; N]; N];
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Move {
     pub x: u8,
     pub y: u8,
@@ -65,14 +97,28 @@ pub struct Move {
 #[derive(Debug, Clone, Copy, PartialEq)]
 /// Error type for game-related errors.
 pub enum GameError {
-    InvalidMove,
+    /// The picked tile is outside the board.
+    OutOfBounds,
+    /// The picked tile is not on the border of the board.
+    InnerTile,
+    /// The picked tile belongs to the opponent.
+    OpponentTile,
+    /// The tile cannot be pushed toward the edge it is already on.
+    IllegalDirection,
+    /// The player to move has no tile to pick up.
     NoValidMoves,
+    /// The game is already won or drawn.
+    GameOver,
 }
 impl Display for GameError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            GameError::InvalidMove => write!(f, "invalid move"),
+            GameError::OutOfBounds => write!(f, "the tile is outside the board"),
+            GameError::InnerTile => write!(f, "inner tiles cannot be moved"),
+            GameError::OpponentTile => write!(f, "tiles of the opponent cannot be moved"),
+            GameError::IllegalDirection => write!(f, "a tile cannot be pushed toward its own edge"),
             GameError::NoValidMoves => write!(f, "no valid moves available"),
+            GameError::GameOver => write!(f, "the game is over"),
         }
     }
 }
@@ -80,165 +126,366 @@ impl std::error::Error for GameError {}
 
 type Result<T> = std::result::Result<T,GameError>;
 
+/// Moves are written as the square of the picked tile followed by the
+/// direction of the push: files `a`, `b`, ... are the columns from left to
+/// right, ranks `1`, `2`, ... the rows from top to bottom, and `<`, `>`, `^`, `v`
+/// push the tile to the left, right, top and bottom edge.
+/// For example `b5<` picks the second tile of the bottom row and pushes it
+/// to the left end of the row. Columns past `z`, only found in moves built
+/// by hand, are written `?`.
 impl Display for Move {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "x: {}, y: {}, shift: {}", self.x, self.y, self.shift)
+        let direction = match self.shift {
+            Shift::TOP => '^',
+            Shift::BOTTOM => 'v',
+            Shift::LEFT => '<',
+            Shift::RIGHT => '>',
+        };
+        let file = char::from_digit(self.x as u32 + 10, 36).unwrap_or('?');
+        write!(f, "{}{}{}", file, self.y as u32 + 1, direction)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Error returned when parsing a move written in the notation of `Move`'s
+/// `Display` implementation.
+pub enum ParseMoveError {
+    /// The move is not made of exactly three characters.
+    Length(usize),
+    InvalidFile(char),
+    InvalidRank(char),
+    InvalidDirection(char),
+}
+
+impl Display for ParseMoveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Length(n) => write!(f, "expected 3 characters (e.g. b5<), found {}", n),
+            Self::InvalidFile(c) => write!(f, "invalid file '{}', expected a letter from a to h", c),
+            Self::InvalidRank(c) => write!(f, "invalid rank '{}', expected a digit from 1 to 8", c),
+            Self::InvalidDirection(c) => write!(f, "invalid direction '{}', expected one of < > ^ v", c),
+        }
+    }
+}
+impl std::error::Error for ParseMoveError {}
+
+impl FromStr for Move {
+    type Err = ParseMoveError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().collect();
+        let [file, rank, direction] = chars[..] else {
+            return Err(ParseMoveError::Length(chars.len()));
+        };
+        let x = match file {
+            'a'..='h' => file as u8 - b'a',
+            _ => return Err(ParseMoveError::InvalidFile(file)),
+        };
+        let y = match rank {
+            '1'..='8' => rank as u8 - b'1',
+            _ => return Err(ParseMoveError::InvalidRank(rank)),
+        };
+        let shift = match direction {
+            '^' => Shift::TOP,
+            'v' => Shift::BOTTOM,
+            '<' => Shift::LEFT,
+            '>' => Shift::RIGHT,
+            _ => return Err(ParseMoveError::InvalidDirection(direction)),
+        };
+        Ok(Move { x, y, shift })
     }
 }
 
 impl Move {
-    pub fn apply(&self, p: Player, b: &Board) -> Result<Board> {
-        let (x, y) = (self.x as usize, self.y as usize);
-        if !(x == 0 || x == 4 || y == 0 ||  y == 4) {
-            return Err(GameError::InvalidMove) // Invalid move
+    /// Check that player p can play the move on the board b.
+    pub fn validate<const N: usize>(&self, p: Player, b: &Board<N>) -> Result<()> {
+        self.validate_with::<N>(|x, y| b[y as usize][x as usize] != Some(p.next()))
+    }
+
+    /// Check the move on a board of size N, `takeable` telling whether the
+    /// player to move may pick up the tile in a cell of the board.
+    pub(crate) fn validate_with<const N: usize>(&self, takeable: impl FnOnce(u8, u8) -> bool) -> Result<()> {
+        let (x, y) = (self.x, self.y);
+        let last = N as u8 - 1;
+        if x > last || y > last {
+            return Err(GameError::OutOfBounds);
+        }
+        if !(x == 0 || x == last || y == 0 || y == last) {
+            return Err(GameError::InnerTile);
         }
-        if !match b[y][x] {
-            Some(player) if player == p => true, // Valid move if the position is occupied by the player
-            None => true, // Valid move if the position is empty
-            _ => false, // Invalid move if the position is occupied by the other player
-        } {
-            return Err(GameError::InvalidMove); // Invalid move
+        if !takeable(x, y) {
+            return Err(GameError::OpponentTile);
         }
-        let mut new_board = b.clone();
+        let toward_own_edge = match self.shift {
+            Shift::TOP => y == 0,
+            Shift::BOTTOM => y == last,
+            Shift::LEFT => x == 0,
+            Shift::RIGHT => x == last,
+        };
+        if toward_own_edge {
+            return Err(GameError::IllegalDirection);
+        }
+        Ok(())
+    }
+
+    pub fn apply<const N: usize>(&self, p: Player, b: &Board<N>) -> Result<Board<N>> {
+        self.validate(p, b)?;
+        let mut new_board = *b;
+        self.shift_board(Some(p), &mut new_board);
+        Ok(new_board)
+    }
+
+    /// Play the move on the board b in place, without checking it, `tile`
+    /// being the tile of the player placed at the edge.
+    pub(crate) fn shift_board<T: Copy, const N: usize>(&self, tile: T, new_board: &mut [[T; N]; N]) {
+        let (x, y, last) = (self.x as usize, self.y as usize, N - 1);
         match self.shift {
             Shift::TOP => {
-                if self.y == 0 {
-                    return Err(GameError::InvalidMove); // Cannot shift up if already at the top
-                }
                 for i in (1..=y).rev() {
                     new_board[i][x] = new_board[i - 1][x]; // Shift down
                 }
-                new_board[0][x] = Some(p); // Place the player piece on the top position
+                new_board[0][x] = tile; // Place the player piece on the top position
             },
             Shift::BOTTOM => {
-                if self.y == 4 {
-                    return Err(GameError::InvalidMove); // Cannot shift down if already at the bottom
-                }
-                for i in y..4 {
+                for i in y..last {
                     new_board[i][x] = new_board[i + 1][x]; // Shift up
                 }
-                new_board[4][x] = Some(p); // Place the player piece on the bottom position
+                new_board[last][x] = tile; // Place the player piece on the bottom position
             },
             Shift::LEFT => {
-                if self.x == 0 {
-                    return Err(GameError::InvalidMove); // Cannot shift left if already at the leftmost position
-                }
                 for i in (1..=x).rev() {
                     new_board[y][i] = new_board[y][i - 1]; // Shift right
                 }
-                new_board[y][0] = Some(p); // Place the player piece on the left position
+                new_board[y][0] = tile; // Place the player piece on the left position
             },
             Shift::RIGHT => {
-                if self.x == 4 {
-                    return Err(GameError::InvalidMove); // Cannot shift right if already at the rightmost position
-                }
-                for i in x..4 {
+                for i in x..last {
                     new_board[y][i] = new_board[y][i + 1]; // Shift left
                 }
-                new_board[y][4] = Some(p); // Place the player piece on the right position
+                new_board[y][last] = tile; // Place the player piece on the right position
             },
         }
-        Ok(new_board)
+    }
+
+    /// Take back the move from the board b in place, `picked` being the
+    /// tile that was picked up by the move.
+    pub(crate) fn unshift_board<T: Copy, const N: usize>(&self, picked: T, b: &mut [[T; N]; N]) {
+        let (x, y) = (self.x as usize, self.y as usize);
+        match self.shift {
+            Shift::TOP => {
+                for i in 0..y {
+                    b[i][x] = b[i + 1][x];
+                }
+            },
+            Shift::BOTTOM => {
+                for i in (y + 1..N).rev() {
+                    b[i][x] = b[i - 1][x];
+                }
+            },
+            Shift::LEFT => {
+                for i in 0..x {
+                    b[y][i] = b[y][i + 1];
+                }
+            },
+            Shift::RIGHT => {
+                for i in (x + 1..N).rev() {
+                    b[y][i] = b[y][i - 1];
+                }
+            },
+        }
+        b[y][x] = picked;
+    }
+
+    /// Apply the move for player p and also return the winner of the
+    /// resulting position under the given rules, see `winner_after_move`.
+    pub fn outcome<const N: usize>(&self, p: Player, b: &Board<N>, rules: Rules) -> Result<(Board<N>, Option<Player>)> {
+        let new_board = self.apply(p, b)?;
+        Ok((new_board, winner_after_move(&new_board, p, rules)))
     }
 }
 
-pub const ALLOWED_MOVES: [Move; 44] = [
-    Move { x: 0, y: 0, shift: Shift::BOTTOM },
-    Move { x: 0, y: 0, shift: Shift::RIGHT },
-    Move { x: 0, y: 1, shift: Shift::TOP },
-    Move { x: 0, y: 1, shift: Shift::BOTTOM },
-    Move { x: 0, y: 1, shift: Shift::RIGHT },
-    Move { x: 0, y: 2, shift: Shift::TOP },
-    Move { x: 0, y: 2, shift: Shift::BOTTOM },
-    Move { x: 0, y: 2, shift: Shift::RIGHT },
-    Move { x: 0, y: 3, shift: Shift::TOP },
-    Move { x: 0, y: 3, shift: Shift::BOTTOM },
-    Move { x: 0, y: 3, shift: Shift::RIGHT },
-    Move { x: 0, y: 4, shift: Shift::TOP },
-    Move { x: 0, y: 4, shift: Shift::RIGHT },
-    Move { x: 1, y: 0, shift: Shift::BOTTOM},
-    Move { x: 1, y: 0, shift: Shift::LEFT },
-    Move { x: 1, y: 0, shift: Shift::RIGHT },
-    // Move { x: 1, y: 1, shift: Shift::TOP },
-    // Move { x: 1, y: 1, shift: Shift::BOTTOM },
-    // Move { x: 1, y: 1, shift: Shift::LEFT },
-    // Move { x: 1, y: 1, shift: Shift::RIGHT },
-    // Move { x: 1, y: 2, shift: Shift::TOP },
-    // Move { x: 1, y: 2, shift: Shift::BOTTOM },
-    // Move { x: 1, y: 2, shift: Shift::LEFT },
-    // Move { x: 1, y: 2, shift: Shift::RIGHT },
-    // Move { x: 1, y: 3, shift: Shift::TOP },
-    // Move { x: 1, y: 3, shift: Shift::BOTTOM },
-    // Move { x: 1, y: 3, shift: Shift::LEFT },
-    // Move { x: 1, y: 3, shift: Shift::RIGHT },
-    Move { x: 1, y: 4, shift: Shift::TOP },
-    Move { x: 1, y: 4, shift: Shift::LEFT },
-    Move { x: 1, y: 4, shift: Shift::RIGHT },
-    Move { x: 2, y: 0, shift: Shift::BOTTOM },
-    Move { x: 2, y: 0, shift: Shift::LEFT },
-    Move { x: 2, y: 0, shift: Shift::RIGHT },
-    // Move { x: 2, y: 1, shift: Shift::TOP },
-    // Move { x: 2, y: 1, shift: Shift::BOTTOM },
-    // Move { x: 2, y: 1, shift: Shift::LEFT },
-    // Move { x: 2, y: 1, shift: Shift::RIGHT },
-    // Move { x: 2, y: 2, shift: Shift::TOP },
-    // Move { x: 2, y: 2, shift: Shift::BOTTOM },
-    // Move { x: 2, y: 2, shift: Shift::LEFT },
-    // Move { x: 2, y: 2, shift: Shift::RIGHT },
-    // Move { x: 2, y: 3, shift: Shift::TOP },
-    // Move { x: 2, y: 3, shift: Shift::BOTTOM },
-    // Move { x: 2, y: 3, shift: Shift::LEFT },
-    // Move { x: 2, y: 3, shift: Shift::RIGHT },
-    Move { x: 2, y: 4, shift: Shift::TOP },
-    Move { x: 2, y: 4, shift: Shift::LEFT },
-    Move { x: 2, y: 4, shift: Shift::RIGHT },
-    Move { x: 3, y: 0, shift: Shift::BOTTOM },
-    Move { x: 3, y: 0, shift: Shift::LEFT },
-    Move { x: 3, y: 0, shift: Shift::RIGHT },
-    // Move { x: 3, y: 1, shift: Shift::TOP },
-    // Move { x: 3, y: 1, shift: Shift::BOTTOM },
-    // Move { x: 3, y: 1, shift: Shift::LEFT },
-    // Move { x: 3, y: 1, shift: Shift::RIGHT },
-    // Move { x: 3, y: 2, shift: Shift::TOP },
-    // Move { x: 3, y: 2, shift: Shift::BOTTOM },
-    // Move { x: 3, y: 2, shift: Shift::LEFT },
-    // Move { x: 3, y: 2, shift: Shift::RIGHT },
-    // Move { x: 3, y: 3, shift: Shift::TOP },
-    // Move { x: 3, y: 3, shift: Shift::BOTTOM },
-    // Move { x: 3, y: 3, shift: Shift::LEFT }, 
-    // Move { x: 3, y: 3, shift: Shift::RIGHT },
-    Move { x: 3, y: 4, shift: Shift::TOP },
-    Move { x: 3, y: 4, shift: Shift::LEFT },
-    Move { x: 3, y: 4, shift: Shift::RIGHT },
-    Move { x: 4, y: 0, shift: Shift::BOTTOM },
-    Move { x: 4, y: 0, shift: Shift::LEFT },
-    Move { x: 4, y: 1, shift: Shift::TOP },
-    Move { x: 4, y: 1, shift: Shift::BOTTOM },
-    Move { x: 4, y: 1, shift: Shift::LEFT },
-    Move { x: 4, y: 2, shift: Shift::TOP },
-    Move { x: 4, y: 2, shift: Shift::BOTTOM },
-    Move { x: 4, y: 2, shift: Shift::LEFT },
-    Move { x: 4, y: 3, shift: Shift::TOP },
-    Move { x: 4, y: 3, shift: Shift::BOTTOM },
-    Move { x: 4, y: 3, shift: Shift::LEFT },
-    Move { x: 4, y: 4, shift: Shift::TOP },
-    Move { x: 4, y: 4, shift: Shift::LEFT }
-];
-
-pub fn find_available_moves(b: &Board, p: Player) -> ([Move; 80], usize) {
-    let mut available_moves: [Move;80] = [Move { x: 0, y: 0, shift: Shift::TOP }; 80];
-    let mut num_available_moves = 0;
-    for mv in ALLOWED_MOVES.into_iter() {
-        if b[mv.y as usize][mv.x as usize].is_none() || b[mv.y as usize][mv.x as usize] == Some(p) {
-            available_moves[num_available_moves] = mv;
-            num_available_moves += 1;
+/// Number of moves allowed on an empty board of size N: 2 for each of the 4
+/// corners and 3 for each of the other border cells.
+pub const fn num_allowed_moves(n: usize) -> usize {
+    12 * n - 16
+}
+
+/// The moves allowed on an empty board of size N, ordered by row, then
+/// column, then direction in `TOP`, `BOTTOM`, `LEFT`, `RIGHT` order,
+/// followed by unused entries up to `MAX_MOVES`.
+pub const fn allowed_moves<const N: usize>() -> [Move; MAX_MOVES] {
+    assert!(N >= 3 && N <= MAX_SIZE, "board sizes from 3 to MAX_SIZE are supported");
+    let last = N as u8 - 1;
+    let mut moves = [Move { x: 0, y: 0, shift: Shift::TOP }; MAX_MOVES];
+    let mut len = 0;
+    let mut y = 0;
+    while y <= last {
+        let mut x = 0;
+        while x <= last {
+            if x == 0 || x == last || y == 0 || y == last {
+                let shifts = [(Shift::TOP, y != 0), (Shift::BOTTOM, y != last), (Shift::LEFT, x != 0), (Shift::RIGHT, x != last)];
+                let mut i = 0;
+                while i < shifts.len() {
+                    if shifts[i].1 {
+                        moves[len] = Move { x, y, shift: shifts[i].0 };
+                        len += 1;
+                    }
+                    i += 1;
+                }
+            }
+            x += 1;
         }
+        y += 1;
+    }
+    assert!(len == num_allowed_moves(N));
+    moves
+}
+
+/// The 44 moves allowed on an empty 5x5 board.
+pub const ALLOWED_MOVES: [Move; 44] = {
+    let all = allowed_moves::<5>();
+    let mut moves = [all[0]; 44];
+    let mut i = 0;
+    while i < 44 {
+        moves[i] = all[i];
+        i += 1;
+    }
+    moves
+};
+
+pub fn find_available_moves<const N: usize>(b: &Board<N>, p: Player) -> ([Move; MAX_MOVES], usize) {
+    Bitboard::from(b).available_moves(p)
+}
+
+/// Moves of player p on the board b leading to distinct boards: when several
+/// moves give the same board, only the first one in `allowed_moves` order is
+/// kept.
+pub fn find_unique_moves<const N: usize>(b: &Board<N>, p: Player) -> ([Move; MAX_MOVES], usize) {
+    let (unique_moves, num_unique_moves) = Bitboard::from(b).unique_moves(p);
+    let mut moves = [Move { x: 0, y: 0, shift: Shift::TOP }; MAX_MOVES];
+    for (m, &(u, _)) in moves.iter_mut().zip(&unique_moves[..num_unique_moves]) {
+        *m = u;
+    }
+    (moves, num_unique_moves)
+}
+
+/// The distinct boards player p can reach from the board b, each with all
+/// the moves leading to it.
+pub fn find_successors<const N: usize>(b: &Board<N>, p: Player) -> Vec<(Board<N>, Vec<Move>)> {
+    let mut successors: Vec<(Board<N>, Vec<Move>)> = Vec::new();
+    let (available_moves, num_available_moves) = find_available_moves(b, p);
+    for &m in &available_moves[..num_available_moves] {
+        let new_board = m.apply(p, b).expect("available moves are valid");
+        match successors.iter_mut().find(|(s, _)| *s == new_board) {
+            Some((_, moves)) => moves.push(m),
+            None => successors.push((new_board, vec![m])),
+        }
+    }
+    successors
+}
+
+/// Rules ending a game in a draw.
+/// Repetitions count the occurrences of a position over the whole game.
+/// Tiles are never removed from the board, so only the positions reached
+/// since the last move that took a neutral tile can match the current one.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DrawRules {
+    /// Number of occurrences of the same position (board and player to move)
+    /// that ends the game in a draw, e.g. 3 for threefold repetition.
+    pub repetitions: Option<u32>,
+    /// Number of plies after which the game is drawn.
+    pub max_plies: Option<u32>,
+}
+
+impl Default for DrawRules {
+    fn default() -> Self {
+        DrawRules { repetitions: Some(3), max_plies: Some(200) }
+    }
+}
+
+impl DrawRules {
+    /// No draw rules: games only end with a winner.
+    pub const NONE: DrawRules = DrawRules { repetitions: None, max_plies: None };
+
+    /// Whether a game is drawn after the given number of plies, the current
+    /// position having occurred the given number of times.
+    pub fn is_draw(&self, plies: u32, repetitions: u32) -> bool {
+        self.max_plies.is_some_and(|m| plies >= m)
+            || self.repetitions.is_some_and(|r| repetitions >= r)
+    }
+}
+
+/// What the draw rules need to know of the game before a position: the
+/// number of plies played, and the Zobrist keys of the positions they left,
+/// oldest first. Searches start from the history of the game to draw their
+/// states like the game would.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct History {
+    plies: u32,
+    keys: Vec<u64>,
+}
+
+impl History {
+    /// The history of a game starting at the position.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn plies(&self) -> u32 {
+        self.plies
+    }
+
+    /// Record a move leaving the position with the given key.
+    pub fn push(&mut self, key: u64) {
+        self.keys.push(key);
+        self.plies += 1;
+    }
+
+    /// Take back the last move, returning the key of the position it left.
+    pub fn pop(&mut self) -> Option<u64> {
+        let key = self.keys.pop()?;
+        self.plies -= 1;
+        Some(key)
+    }
+
+    /// Number of times the position with the given key occurred before.
+    pub fn occurrences(&self, key: u64) -> u32 {
+        self.keys.iter().filter(|&&k| k == key).count() as u32
+    }
+}
+
+/// Variants of the rules of the game. The default rules are the official
+/// ones: lines across the whole board, diagonals included, and the draw
+/// rules of `DrawRules::default`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rules {
+    /// Number of aligned tiles making a line, None for lines across the
+    /// whole board.
+    pub win_length: Option<u8>,
+    /// Whether diagonal lines count.
+    pub diagonals: bool,
+    /// Misère variant: the owner of a line loses instead of winning.
+    pub misere: bool,
+    pub draw: DrawRules,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules { win_length: None, diagonals: true, misere: false, draw: DrawRules::default() }
+    }
+}
+
+impl Rules {
+    /// Number of aligned tiles making a line on a board of size n.
+    pub fn win_length(&self, n: usize) -> usize {
+        self.win_length.map_or(n, usize::from)
     }
-    (available_moves, num_available_moves)
 }
 
 /// Generate a random move for player p on the board b.
-pub fn random_move(b: &Board, p: Player) -> Result<Move> {
+pub fn random_move<const N: usize>(b: &Board<N>, p: Player) -> Result<Move> {
     let mut rng = rand::rng();
     let (available_moves, num_available_moves) = find_available_moves(b, p);
     if num_available_moves == 0 {
@@ -249,29 +496,14 @@ pub fn random_move(b: &Board, p: Player) -> Result<Move> {
 }
 
 /// Play a random game starting from the board b with the player p.
-/// The game ends when one of the players has 5 in a row, column, or diagonal.
-/// Return the winner player.
-pub fn random_game(mut b: Board, mut player: Player) -> Option<Player> {
-    loop {
-        if let Some(winner_player) = winner(&b) {
-            return Some(winner_player); // Return the winner if found
-        }
-        match random_move(&b, player) {
-            Ok(mv) => {
-                b = mv.apply(player, &b).unwrap();
-                //println!("Player {} made a move: {}", player, mv);
-                //print_board(&b);
-                player = if player == Player::X { Player::O } else { Player::X }; 
-            },
-            Err(_) => {
-                // No valid moves available, end the game
-                return None; // Return the last player who made a move
-            }
-        }
-    }
+/// The game ends when one of the players wins or in a draw according to the
+/// rules.
+/// Return the winner player, None for a draw.
+pub fn random_game<const N: usize>(b: Board<N>, player: Player, rules: Rules) -> Option<Player> {
+    bitboard::random_game(Bitboard::from(b), player, rules)
 }
 
-pub fn print_board(b: &Board) {
+pub fn print_board<const N: usize>(b: &Board<N>) {
     for row in b.iter() {
         for cell in row.iter() {
             match cell {
@@ -284,61 +516,203 @@ pub fn print_board(b: &Board) {
     println!();
 }
 
-pub fn winner(b: &Board) -> Option<Player> {
-    // If a player has 5 in a row, column, or diagonal, return that player
-    for i in 0..5 {
-        // Check rows
-        if let Some(player) = b[i][0] {
-            if b[i].iter().all(|&cell| cell == Some(player)) {
-                return Some(player);
-            }
+/// If a player has a line under the given rules, return the winner.
+/// This ignores who moved last, so when both players have a line the result
+/// is arbitrary: use `winner_after_move` to score a game.
+pub fn winner<const N: usize>(b: &Board<N>, rules: Rules) -> Option<Player> {
+    Bitboard::from(b).winner(rules)
+}
+
+/// Winner of the board b reached by a move of player `mover`.
+/// A move that completes a line of the opponent loses, even when it also
+/// completes a line of the mover. See `Bitboard::winner_after_move` for the
+/// misère variant.
+pub fn winner_after_move<const N: usize>(b: &Board<N>, mover: Player, rules: Rules) -> Option<Player> {
+    Bitboard::from(b).winner_after_move(mover, rules)
+}
+
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GameStatus {
+    Ongoing,
+    Won(Player),
+    Drawn,
+}
+
+impl Display for GameStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ongoing => write!(f, "ongoing"),
+            Self::Won(p) => write!(f, "{} wins", p),
+            Self::Drawn => write!(f, "draw"),
         }
-        // Check columns
-        if let Some(player) = b[0][i] {
-            if b.iter().all(|row| row[i] == Some(player)) {
-                return Some(player);
-            }
+    }
+}
+
+/// A game in progress: the states reached so far and the status, with undo
+/// and redo of the played moves. `Game` plays the two-player game and
+/// `team::TeamGame` the four-player one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameRecord<S> {
+    /// States reached so far, starting with the initial one.
+    states: Vec<S>,
+    moves: Vec<Move>,
+    /// Undone moves, the next one to redo last.
+    undone: Vec<Move>,
+    status: GameStatus,
+    rules: Rules,
+}
+
+/// A two-player game on a board of size N.
+pub type Game<const N: usize = 5> = GameRecord<GameState<N>>;
+
+impl<S: SearchState> GameRecord<S> {
+    /// A new game starting from the given state, played according to the
+    /// given rules.
+    pub fn from_state(state: S, rules: Rules) -> Self {
+        let mut game = GameRecord {
+            states: vec![state],
+            moves: Vec::new(),
+            undone: Vec::new(),
+            status: GameStatus::Ongoing,
+            rules,
+        };
+        game.status = game.compute_status();
+        game
+    }
+
+    pub fn state(&self) -> &S {
+        self.states.last().expect("a game always has a state")
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.rules
+    }
+
+    /// Number of times the current state occurred in the game.
+    pub fn repetitions(&self) -> u32 {
+        self.states.iter().filter(|&s| s == self.state()).count() as u32
+    }
+
+    /// History of the states before the current one, for searches.
+    pub fn history(&self) -> History {
+        let mut history = History::new();
+        for s in &self.states[..self.moves.len()] {
+            history.push(s.key());
+        }
+        history
+    }
+
+    pub fn status(&self) -> GameStatus {
+        self.status
+    }
+
+    pub fn winner(&self) -> Option<Player> {
+        match self.status {
+            GameStatus::Won(p) => Some(p),
+            _ => None,
         }
     }
-    // Check diagonals
-    if let Some(player) = b[0][0] {
-        if (1..5).all(|i| b[i][i] == Some(player)) {
-            return Some(player);
+
+    /// Moves played so far, in order.
+    pub fn moves(&self) -> &[Move] {
+        &self.moves
+    }
+
+    /// Play the move m for the side to move and return the new status.
+    pub fn play(&mut self, m: Move) -> Result<GameStatus> {
+        self.push(m)?;
+        self.undone.clear();
+        Ok(self.status)
+    }
+
+    fn push(&mut self, m: Move) -> Result<()> {
+        if self.status != GameStatus::Ongoing {
+            return Err(GameError::GameOver);
         }
+        let state = self.state().play(m)?;
+        self.states.push(state);
+        self.moves.push(m);
+        self.status = self.compute_status();
+        Ok(())
+    }
+
+    /// Take back the last move, returning it.
+    pub fn undo(&mut self) -> Option<Move> {
+        let m = self.moves.pop()?;
+        self.states.pop();
+        self.undone.push(m);
+        self.status = self.compute_status();
+        Some(m)
     }
-    if let Some(player) = b[0][4] {
-        if (1..5).all(|i| b[i][4 - i] == Some(player)) {
-            return Some(player);
+
+    /// Play again the last undone move, returning it.
+    pub fn redo(&mut self) -> Option<Move> {
+        let m = self.undone.pop()?;
+        self.push(m).expect("undone moves are legal");
+        Some(m)
+    }
+
+    fn compute_status(&self) -> GameStatus {
+        if let Some(p) = self.state().winner(self.rules) {
+            GameStatus::Won(p)
+        } else if self.state().available_moves().1 == 0
+            || self.rules.draw.is_draw(self.moves.len() as u32, self.repetitions()) {
+            GameStatus::Drawn
+        } else {
+            GameStatus::Ongoing
         }
     }
-    None
+}
 
+impl<const N: usize> Default for Game<N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+impl<const N: usize> Game<N> {
+    /// A new game on the empty board, X moves first.
+    pub fn new() -> Self {
+        Self::from_position([[None; N]; N], Player::X)
+    }
+
+    /// A new game starting from board b with player p to move.
+    pub fn from_position(b: Board<N>, p: Player) -> Self {
+        Self::with_rules(b, p, Rules::default())
+    }
+
+    /// A new game starting from board b with player p to move, played
+    /// according to the given rules.
+    pub fn with_rules(b: Board<N>, p: Player, rules: Rules) -> Self {
+        Self::from_state(GameState::new(b, p), rules)
+    }
+
+    pub fn board(&self) -> &Board<N> {
+        self.state().board()
+    }
+
+    /// The player to move.
+    pub fn player(&self) -> Player {
+        self.state().player()
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::position::parse_board;
+    use crate::testing::{state, MIDGAME, MIDGAME_WON};
 
-    const B: Board = [
-        [Some(Player::X), Some(Player::X), Some(Player::X), None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [None, Some(Player::X), None, None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [Some(Player::X), None, Some(Player::O), None, None],
-    ];
-    const B_WON: Board = [
-        [Some(Player::X), Some(Player::X), Some(Player::X), None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [None, Some(Player::X), None, None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [Some(Player::X), Some(Player::X), Some(Player::O), None, None],
-    ];
+    fn board(s: &str) -> Board {
+        parse_board(s).unwrap()
+    }
 
     #[test]
     fn test_random_game() {
         let board: Board = [[None; 5]; 5];
-        let winner = random_game(board, Player::X);
+        let winner = random_game(board, Player::X, Rules::default());
         assert!(winner.is_none() || winner == Some(Player::X) || winner == Some(Player::O));
     }
 
@@ -350,7 +724,7 @@ mod tests {
         board[0][2] = Some(Player::X);
         board[0][3] = Some(Player::X);
         board[0][4] = Some(Player::X);
-        assert_eq!(winner(&board), Some(Player::X));
+        assert_eq!(winner(&board, Rules::default()), Some(Player::X));
 
         board = [[None; 5]; 5];
         board[0][0] = Some(Player::O);
@@ -358,16 +732,238 @@ mod tests {
         board[2][2] = Some(Player::O);
         board[3][3] = Some(Player::O);
         board[4][4] = Some(Player::O);
-        assert_eq!(winner(&board), Some(Player::O));
+        assert_eq!(winner(&board, Rules::default()), Some(Player::O));
     }
 
     #[test]
     fn test_move_x_almost_won() {
-        let b = B;
-        let b_new = B_WON;
+        let b = *state(MIDGAME).board();
+        let b_new = *state(MIDGAME_WON).board();
         let p = Player::X;
         let m = Move {x: 1, y: 4, shift: Shift::LEFT};
         assert_eq!(m.apply(p, &b), Ok(b_new));
+        assert_eq!(m.outcome(p, &b, Rules::default()), Ok((b_new, Some(Player::X))));
+    }
+
+    #[test]
+    fn test_illegal_move_reasons() {
+        let b = *state(MIDGAME).board();
+        let p = Player::X;
+        let m = |x, y, shift| Move {x, y, shift};
+        assert_eq!(m(5, 0, Shift::LEFT).validate(p, &b), Err(GameError::OutOfBounds));
+        assert_eq!(m(2, 2, Shift::LEFT).validate(p, &b), Err(GameError::InnerTile));
+        assert_eq!(m(0, 1, Shift::TOP).validate(p, &b), Err(GameError::OpponentTile));
+        assert_eq!(m(0, 2, Shift::LEFT).validate(p, &b), Err(GameError::IllegalDirection));
+        assert_eq!(m(4, 4, Shift::BOTTOM).apply(p, &b), Err(GameError::IllegalDirection));
+        assert_eq!(m(0, 1, Shift::TOP).validate(Player::O, &b), Ok(()));
+        for mv in ALLOWED_MOVES {
+            assert_eq!(mv.validate(p, &b).is_ok(), mv.apply(p, &b).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_allowed_moves() {
+        fn check<const N: usize>() {
+            let moves = allowed_moves::<N>();
+            let empty: Board<N> = [[None; N]; N];
+            let legal: Vec<Move> = (0..N as u8).flat_map(|y| (0..N as u8).flat_map(move |x| {
+                [Shift::TOP, Shift::BOTTOM, Shift::LEFT, Shift::RIGHT].map(|shift| Move {x, y, shift})
+            })).filter(|m| m.validate(Player::X, &empty).is_ok()).collect();
+            assert_eq!(moves[..num_allowed_moves(N)], legal[..]);
+            assert_eq!(find_available_moves(&empty, Player::X).1, num_allowed_moves(N));
+        }
+        check::<3>();
+        check::<4>();
+        check::<5>();
+        check::<6>();
+        check::<8>();
+        assert_eq!(ALLOWED_MOVES[..], allowed_moves::<5>()[..44]);
+        assert_eq!(num_allowed_moves(MAX_SIZE), MAX_MOVES);
+    }
+
+    #[test]
+    fn test_game_on_smaller_board() {
+        let mut game: Game<3> = Game::new();
+        for m in ["a1>", "a3>", "a1>", "c3<"] {
+            assert_eq!(game.play(m.parse().unwrap()), Ok(GameStatus::Ongoing));
+        }
+        assert_eq!(game.play("a1>".parse().unwrap()), Ok(GameStatus::Won(Player::X)));
+        assert_eq!(game.board()[0], [Some(Player::X); 3]);
+        let mut game: Game<4> = Game::new();
+        assert_eq!(game.play("c3^".parse().unwrap()), Err(GameError::InnerTile));
+        assert_eq!(game.play("e1<".parse().unwrap()), Err(GameError::OutOfBounds));
+        assert_eq!(game.play("d1<".parse().unwrap()), Ok(GameStatus::Ongoing));
+    }
+
+    #[test]
+    fn test_unique_moves() {
+        let b = *state(MIDGAME).board();
+        let (moves, n) = find_available_moves(&b, Player::X);
+        let (unique_moves, n_unique) = find_unique_moves(&b, Player::X);
+        let successors = find_successors(&b, Player::X);
+        assert_eq!(n, 35);
+        assert_eq!(n_unique, successors.len());
+        assert!(n_unique < n);
+        // every move is grouped with the others giving the same board
+        assert_eq!(successors.iter().map(|(_, m)| m.len()).sum::<usize>(), n);
+        for (s, group) in successors.iter() {
+            assert!(group.iter().all(|m| m.apply(Player::X, &b) == Ok(*s)));
+            assert!(unique_moves[..n_unique].contains(&group[0]));
+        }
+        let won = successors.iter().find(|(s, _)| *s == *state(MIDGAME_WON).board()).unwrap();
+        assert_eq!(won.1, ["b5^".parse().unwrap(), "b5<".parse().unwrap()]);
+        assert!(moves[..n].iter().all(|m| successors.iter().any(|(_, g)| g.contains(m))));
+    }
+
+    #[test]
+    fn test_game_rejects_moves_when_over() {
+        let mut game = Game::from_position(*state(MIDGAME).board(), Player::X);
+        assert_eq!(game.status(), GameStatus::Ongoing);
+        let m = Move {x: 1, y: 4, shift: Shift::LEFT};
+        assert_eq!(game.play(m), Ok(GameStatus::Won(Player::X)));
+        assert_eq!(game.board(), state(MIDGAME_WON).board());
+        assert_eq!(game.player(), Player::O);
+        assert_eq!(game.play(Move {x: 4, y: 4, shift: Shift::LEFT}), Err(GameError::GameOver));
+        assert_eq!(game.moves(), &[m]);
+    }
+
+    #[test]
+    fn test_game_undo_redo() {
+        let mut game: Game = Game::new();
+        let m1 = Move {x: 0, y: 0, shift: Shift::RIGHT};
+        let m2 = Move {x: 4, y: 4, shift: Shift::TOP};
+        game.play(m1).unwrap();
+        game.play(m2).unwrap();
+        let b = *game.board();
+        assert_eq!(game.undo(), Some(m2));
+        assert_eq!(game.player(), Player::O);
+        assert_eq!(game.undo(), Some(m1));
+        assert_eq!(game.undo(), None);
+        assert_eq!(game.board(), &[[None; 5]; 5]);
+        assert_eq!(game.redo(), Some(m1));
+        assert_eq!(game.redo(), Some(m2));
+        assert_eq!(game.redo(), None);
+        assert_eq!(game.board(), &b);
+        // playing a new move drops the moves left to redo
+        game.undo();
+        game.play(m1).unwrap();
+        assert_eq!(game.redo(), None);
+        assert_eq!(game.moves(), &[m1, m1]);
+    }
+
+    #[test]
+    fn test_game_drawn_by_repetition() {
+        let mut game: Game = Game::new();
+        game.play(Move {x: 0, y: 0, shift: Shift::RIGHT}).unwrap();
+        game.play(Move {x: 0, y: 4, shift: Shift::RIGHT}).unwrap();
+        // X and O shuffle their tiles back and forth along the top and bottom rows
+        let cycle = [
+            Move {x: 4, y: 0, shift: Shift::LEFT},
+            Move {x: 4, y: 4, shift: Shift::LEFT},
+            Move {x: 0, y: 0, shift: Shift::RIGHT},
+            Move {x: 0, y: 4, shift: Shift::RIGHT},
+        ];
+        let start = *game.board();
+        for m in cycle.iter().chain(cycle.iter()) {
+            assert_eq!(game.status(), GameStatus::Ongoing);
+            game.play(*m).unwrap();
+        }
+        assert_eq!(game.board(), &start);
+        assert_eq!(game.repetitions(), 3);
+        let history = game.history();
+        assert_eq!(history.plies(), 10);
+        assert_eq!(history.occurrences(game.state().key()) + 1, game.repetitions());
+        assert_eq!(game.status(), GameStatus::Drawn);
+        assert_eq!(game.play(cycle[0]), Err(GameError::GameOver));
+        game.undo();
+        assert_eq!(game.status(), GameStatus::Ongoing);
+    }
+
+    #[test]
+    fn test_game_drawn_by_move_limit() {
+        let rules = Rules { draw: DrawRules { repetitions: None, max_plies: Some(2) }, ..Rules::default() };
+        let mut game = Game::with_rules([[None; 5]; 5], Player::X, rules);
+        game.play(Move {x: 0, y: 0, shift: Shift::RIGHT}).unwrap();
+        assert_eq!(game.play(Move {x: 4, y: 4, shift: Shift::LEFT}), Ok(GameStatus::Drawn));
+    }
+
+    #[test]
+    fn test_move_notation_round_trip() {
+        let m = Move {x: 1, y: 4, shift: Shift::LEFT};
+        assert_eq!(m.to_string(), "b5<");
+        assert_eq!("b5<".parse(), Ok(m));
+        assert_eq!("e1v".parse(), Ok(Move {x: 4, y: 0, shift: Shift::BOTTOM}));
+        // coordinates off any board are written without overflowing
+        assert_eq!(Move {x: 25, y: 255, shift: Shift::TOP}.to_string(), "z256^");
+        assert_eq!(Move {x: 255, y: 0, shift: Shift::TOP}.to_string(), "?1^");
+        for x in 0..MAX_SIZE as u8 {
+            for y in 0..MAX_SIZE as u8 {
+                for shift in [Shift::TOP, Shift::BOTTOM, Shift::LEFT, Shift::RIGHT] {
+                    let m = Move {x, y, shift};
+                    assert_eq!(m.to_string().parse(), Ok(m));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_move_notation_errors() {
+        assert_eq!("".parse::<Move>(), Err(ParseMoveError::Length(0)));
+        assert_eq!("b5<<".parse::<Move>(), Err(ParseMoveError::Length(4)));
+        assert_eq!("i5<".parse::<Move>(), Err(ParseMoveError::InvalidFile('i')));
+        assert_eq!("B5<".parse::<Move>(), Err(ParseMoveError::InvalidFile('B')));
+        assert_eq!("b0<".parse::<Move>(), Err(ParseMoveError::InvalidRank('0')));
+        assert_eq!("b9<".parse::<Move>(), Err(ParseMoveError::InvalidRank('9')));
+        assert_eq!("b5L".parse::<Move>(), Err(ParseMoveError::InvalidDirection('L')));
+        // parsing does not check that the move is legal
+        assert_eq!("c3^".parse(), Ok(Move {x: 2, y: 2, shift: Shift::TOP}));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let m = Move {x: 1, y: 4, shift: Shift::LEFT};
+        let json = serde_json::to_string(&m).unwrap();
+        assert_eq!(json, r#"{"x":1,"y":4,"shift":"LEFT"}"#);
+        assert_eq!(serde_json::from_str::<Move>(&json).unwrap(), m);
+        let b = *state(MIDGAME).board();
+        let json = serde_json::to_string(&b).unwrap();
+        assert_eq!(serde_json::from_str::<Board>(&json).unwrap(), b);
+        let json = serde_json::to_string(&GameStatus::Won(Player::O)).unwrap();
+        assert_eq!(json, r#"{"Won":"O"}"#);
+    }
+
+    #[test]
+    fn test_move_completing_both_lines_loses() {
+        let b = board("X1O2/X1O2/X1O2/X1O2/1O3");
+        let m = Move {x: 4, y: 4, shift: Shift::LEFT};
+        let (b_new, w) = m.outcome(Player::X, &b, Rules::default()).unwrap();
+        assert_eq!(b_new, board("X1O2/X1O2/X1O2/X1O2/X1O2"));
+        assert_eq!(w, Some(Player::O));
+    }
+
+    #[test]
+    fn test_game_with_rule_variants() {
+        let rules = Rules { win_length: Some(4), diagonals: false, ..Rules::default() };
+        let mut game = Game::with_rules(board("XXX2/5/5/5/4O"), Player::X, rules);
+        assert_eq!(game.rules(), rules);
+        assert_eq!(game.play("e1<".parse().unwrap()), Ok(GameStatus::Won(Player::X)));
+        let misere = Rules { misere: true, ..rules };
+        let mut game = Game::with_rules(board("XXX2/5/5/5/4O"), Player::X, misere);
+        assert_eq!(game.play("e1<".parse().unwrap()), Ok(GameStatus::Won(Player::O)));
+        // four on a diagonal only wins when diagonals count
+        let mut game = Game::with_rules(board("5/1X3/2X2/3X1/5"), Player::X, rules);
+        assert_eq!(game.play("e1v".parse().unwrap()), Ok(GameStatus::Ongoing));
+        let mut game = Game::with_rules(board("5/1X3/2X2/3X1/5"), Player::X, Rules { diagonals: true, ..rules });
+        assert_eq!(game.play("e1v".parse().unwrap()), Ok(GameStatus::Won(Player::X)));
+    }
+
+    #[test]
+    fn test_move_completing_opponent_line_loses() {
+        let b = board("2O2/2O2/2O2/2O2/1O3");
+        let m = Move {x: 4, y: 4, shift: Shift::LEFT};
+        assert_eq!(m.outcome(Player::X, &b, Rules::default()).unwrap().1, Some(Player::O));
+        assert_eq!(winner_after_move(&b, Player::X, Rules::default()), None);
     }
 
 }
\ No newline at end of file
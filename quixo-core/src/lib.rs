@@ -1,4 +1,17 @@
+pub mod agent;
+pub mod bitboard;
+pub mod cancel;
+pub mod eval;
 pub mod game;
 pub mod simulations;
+pub mod symmetry;
 pub mod mcts;
-pub mod cli;
\ No newline at end of file
+pub mod negamax;
+pub mod position;
+pub mod perft;
+pub mod team;
+pub mod tournament;
+pub mod zobrist;
+pub mod cli;
+#[cfg(test)]
+mod testing;
\ No newline at end of file
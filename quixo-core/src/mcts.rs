@@ -1,21 +1,217 @@
 use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
-use std::sync::mpsc;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::cancel::CancelToken;
 use crate::simulations::{Result, parallel_simulation};
-use crate::game::{find_available_moves, winner, Board, Move, Player};
+use crate::game::{find_available_moves, find_unique_moves, winner_after_move, Board, DrawRules, Game, GameError, History, Move, Player, Rules, MAX_MOVES};
+use crate::eval::{Evaluator, RolloutCutoff};
+use crate::zobrist::{self, BuildZobristHasher};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct GameState {
-    pub board: Board,
-    pub player: Player,
+/// A board of size N with the player to move, and the Zobrist keys of the
+/// state and of its symmetric states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "GameStateData", into = "GameStateData"))]
+pub struct GameState<const N: usize = 5> {
+    board: Board<N>,
+    player: Player,
+    /// Keys of the 8 symmetric states in `Symmetry::ALL` order, that of the
+    /// state first.
+    keys: [u64; 8],
+}
+
+/// Hashes the Zobrist key only.
+impl<const N: usize> Hash for GameState<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.key());
+    }
+}
+
+impl<const N: usize> GameState<N> {
+    pub fn new(board: Board<N>, player: Player) -> Self {
+        GameState { board, player, keys: zobrist::symmetric_keys(&board, player) }
+    }
+
+    pub fn board(&self) -> &Board<N> {
+        &self.board
+    }
+
+    /// The player to move.
+    pub fn player(&self) -> Player {
+        self.player
+    }
+
+    /// The Zobrist key of the state.
+    pub fn key(&self) -> u64 {
+        self.keys[0]
+    }
+
+    /// A key shared by the 8 symmetric states: the smallest of their keys.
+    pub fn symmetric_key(&self) -> u64 {
+        *self.keys.iter().min().expect("there are 8 keys")
+    }
+
+    /// The state reached when the player to move plays m. The keys are
+    /// updated incrementally.
+    pub fn play(&self, m: Move) -> std::result::Result<GameState<N>, GameError> {
+        let board = m.apply(self.player, &self.board)?;
+        let keys = zobrist::update_symmetric(self.keys, &self.board, m, self.player);
+        Ok(GameState { board, player: self.player.next(), keys })
+    }
+
+    /// Play m in place for the player to move, returning what is needed to
+    /// take it back with `unmake_move`.
+    pub fn make_move(&mut self, m: Move) -> std::result::Result<MoveUndo, GameError> {
+        m.validate(self.player, &self.board)?;
+        let undo = MoveUndo {
+            m,
+            player: self.player,
+            picked: self.board[m.y as usize][m.x as usize],
+            keys: self.keys,
+        };
+        self.keys = zobrist::update_symmetric(self.keys, &self.board, m, self.player);
+        m.shift_board(Some(self.player), &mut self.board);
+        self.player = self.player.next();
+        Ok(undo)
+    }
+
+    /// Take back the last move made with `make_move`.
+    pub fn unmake_move(&mut self, undo: MoveUndo) {
+        undo.m.unshift_board(undo.picked, &mut self.board);
+        self.player = undo.player;
+        self.keys = undo.keys;
+    }
+
+    /// Winner of the state under the given rules, the last move having been
+    /// made by the player who is not to move.
+    pub fn winner(&self, rules: Rules) -> Option<Player> {
+        winner_after_move(&self.board, self.player.next(), rules)
+    }
+}
+
+/// A position the search can explore: `GameState` in the two-player game
+/// and `TeamState` in the four-player team game. Statistics are kept for the
+/// two sides X and O.
+pub trait SearchState: Copy + Eq + Send + Sync {
+    /// The side to move.
+    fn side(&self) -> Player;
+    fn key(&self) -> u64;
+    /// A key shared by the states symmetric to this one.
+    fn symmetric_key(&self) -> u64;
+    /// Winner of the state under the given rules.
+    fn winner(&self, rules: Rules) -> Option<Player>;
+    /// Legal moves of the side to move.
+    fn available_moves(&self) -> ([Move; MAX_MOVES], usize);
+    /// Moves of the side to move leading to distinct states.
+    fn unique_moves(&self) -> ([Move; MAX_MOVES], usize);
+    fn play(&self, m: Move) -> std::result::Result<Self, GameError>;
+    /// Play n random games from the state.
+    fn rollouts(&self, n: u32, rules: Rules) -> Result;
+}
+
+impl<const N: usize> SearchState for GameState<N> {
+    fn side(&self) -> Player {
+        self.player
+    }
+
+    fn key(&self) -> u64 {
+        GameState::key(self)
+    }
+
+    fn symmetric_key(&self) -> u64 {
+        GameState::symmetric_key(self)
+    }
+
+    fn winner(&self, rules: Rules) -> Option<Player> {
+        GameState::winner(self, rules)
+    }
+
+    fn available_moves(&self) -> ([Move; MAX_MOVES], usize) {
+        find_available_moves(&self.board, self.player)
+    }
+
+    fn unique_moves(&self) -> ([Move; MAX_MOVES], usize) {
+        find_unique_moves(&self.board, self.player)
+    }
+
+    fn play(&self, m: Move) -> std::result::Result<Self, GameError> {
+        GameState::play(self, m)
+    }
+
+    fn rollouts(&self, n: u32, rules: Rules) -> Result {
+        parallel_simulation(self.board, self.player, n, rules)
+    }
+}
+
+/// What `GameState::unmake_move` needs to restore the state before a move:
+/// the tiles of the row or column other than the picked one have only slid
+/// by one cell, so the picked tile is the only one to remember.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveUndo {
+    m: Move,
+    player: Player,
+    picked: Option<Player>,
+    keys: [u64; 8],
+}
+
+impl<const N: usize> From<&Game<N>> for GameState<N> {
+    fn from(game: &Game<N>) -> Self {
+        *game.state()
+    }
+}
+
+/// Serialized form of `GameState`, without the key. The board is a vector
+/// of rows, as serde only implements its traits for arrays of given sizes.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct GameStateData {
+    board: Vec<Vec<Option<Player>>>,
+    player: Player,
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> TryFrom<GameStateData> for GameState<N> {
+    type Error = crate::position::ParsePositionError;
+
+    fn try_from(data: GameStateData) -> std::result::Result<Self, Self::Error> {
+        use crate::position::ParsePositionError;
+        if data.board.len() != N {
+            return Err(ParsePositionError::RowCount(data.board.len()));
+        }
+        let mut board: Board<N> = [[None; N]; N];
+        for (y, row) in data.board.into_iter().enumerate() {
+            board[y] = row.try_into().map_err(|_| ParsePositionError::RowLength(y))?;
+        }
+        Ok(GameState::new(board, data.player))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> From<GameState<N>> for GameStateData {
+    fn from(state: GameState<N>) -> Self {
+        GameStateData { board: state.board.iter().map(|row| row.to_vec()).collect(), player: state.player }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MCTSNode {
     // pub game_state: GameState,
+    /// Rollouts played from the state or below it.
     pub visits: u32,
     pub x_wins: u32,
-    pub o_wins: u32
+    pub o_wins: u32,
+    /// Iterations whose walk went through the state.
+    pub iterations: u32,
+}
+
+impl Default for MCTSNode {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MCTSNode {
@@ -24,184 +220,850 @@ impl MCTSNode {
             visits: 0,
             x_wins: 0,
             o_wins: 0,
+            iterations: 0,
+        }
+    }
+
+    pub fn draws(&self) -> u32 {
+        self.visits - self.x_wins - self.o_wins
+    }
+
+    /// Rollouts won by side p.
+    pub fn wins(&self, p: Player) -> u32 {
+        match p {
+            Player::X => self.x_wins,
+            Player::O => self.o_wins,
+        }
+    }
+
+    fn wins_mut(&mut self, p: Player) -> &mut u32 {
+        match p {
+            Player::X => &mut self.x_wins,
+            Player::O => &mut self.o_wins,
+        }
+    }
+
+    /// Average result of the rollouts for side p, a win counting 1 and a
+    /// draw 1/2, or 0 without visits.
+    pub fn value(&self, p: Player) -> f64 {
+        if self.visits == 0 {
+            return 0.0;
+        }
+        (self.wins(p) as f64 + self.draws() as f64 / 2.0) / self.visits as f64
+    }
+
+    /// Average square of the results of the rollouts for side p, for the
+    /// variance used by UCB1-Tuned.
+    fn square_value(&self, p: Player) -> f64 {
+        if self.visits == 0 {
+            return 0.0;
         }
+        (self.wins(p) as f64 + self.draws() as f64 / 4.0) / self.visits as f64
     }
 }
 
-type NodeTable = HashMap<GameState, MCTSNode>;
+/// Formula scoring the children of a state during the descent, with n the
+/// iterations through the child, N those through the parent, Q the value of
+/// the child for the side to move and c the exploration constant.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Selection {
+    /// Q + c·sqrt(ln N / n).
+    #[default]
+    Ucb1,
+    /// UCB1 with the exploration term bounded by the variance of the
+    /// results of the child, scaled by c/√2 so that the default constant
+    /// gives the textbook formula.
+    Ucb1Tuned,
+    /// Q + c·P·sqrt(N) / (1 + n), with the same prior P for every move
+    /// and unvisited children valued 1/2.
+    Puct,
+}
 
-fn find_child_states(current_state: GameState, available_moves: &[Move]) -> ([GameState;80], usize) {
-    let mut child_states = [GameState{board: [[None;5];5], player: Player::X}; 80];
-    let mut len = 0;
-    for (_, &m) in available_moves.iter().enumerate() {
-        child_states[len] = GameState{board: m.apply(current_state.player, &current_state.board).unwrap(), player: current_state.player.next()};
-        len += 1;
+/// Which child of the root the search plays once it stops.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FinalMove {
+    #[default]
+    MostVisits,
+    BestValue,
+    /// The most visited child once it also has the best value: the search
+    /// goes on for up to a tenth more iterations until it does.
+    RobustMax,
+}
+
+/// Settings of an MCTS search. The n and N of the selection formula count
+/// iterations, whatever the number of rollouts per leaf, while the values
+/// average the results of the rollouts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MctsConfig {
+    pub exploration: f64,
+    pub selection: Selection,
+    /// Random games played from the leaf reached by each iteration, in
+    /// parallel.
+    pub rollouts_per_leaf: u32,
+    pub final_move: FinalMove,
+    /// Whether symmetric states share their statistics.
+    pub symmetric: bool,
+    pub parallelism: Parallelism,
+}
+
+/// Textbook UCB1 with c = √2, 100 rollouts per leaf, the most visited
+/// move, and symmetries shared.
+impl Default for MctsConfig {
+    fn default() -> Self {
+        MctsConfig {
+            exploration: std::f64::consts::SQRT_2,
+            selection: Selection::Ucb1,
+            rollouts_per_leaf: 100,
+            final_move: FinalMove::MostVisits,
+            symmetric: true,
+            parallelism: Parallelism::Leaf,
+        }
     }
-    (child_states, len)
 }
 
-fn calculate_ucb_scores(node_table: &NodeTable, parent_state: GameState, child_states: &[GameState]) -> ([f64; 80], usize) {
-    let mut scores: [f64; 80] = [0.0;80];
-    let mut len: usize = 0;
-    let mut n_visits: u32 = 0;
-    child_states.iter()
-        .map(|s| node_table.get(s))
-        .map(|s| s.map_or((0,0,0), |n| (n.visits, n.x_wins, n.o_wins)))
-        .for_each(|(visits, x_wins, o_wins)| {
-            n_visits += visits;
-            let wins = match parent_state.player {
-                Player::X => x_wins,
-                Player::O => o_wins
-            };
-            scores[len] = match visits {
-                0 => std::f64::INFINITY,
-                _ => (wins as f64) / (visits as f64) * (1.0 / visits as f64).sqrt()
+/// How a search uses several threads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Parallelism {
+    /// One walk down the tree at a time, the rollouts of its leaf played in
+    /// parallel.
+    #[default]
+    Leaf,
+    /// Threads walking down the same tree at once, each state on the way
+    /// counting as `virtual_loss` lost rollouts until the walk is done.
+    Tree { threads: usize, virtual_loss: u32 },
+    /// Threads growing trees of their own from the root, whose statistics
+    /// are added up at the end. The progress reported is that of the first
+    /// tree, and a node limit counts the nodes added by all the trees.
+    Root { threads: usize },
+}
+
+/// Statistics of the states visited by the search, by Zobrist key. When
+/// symmetries are shared, each state is stored under the smallest key of its
+/// symmetric states so that the 8 of them share one node.
+#[derive(Debug, Default, Clone)]
+struct NodeTable {
+    nodes: HashMap<u64, MCTSNode, BuildZobristHasher>,
+    symmetric: bool,
+}
+
+/// Where a search keeps its statistics: `NodeTable` for a single thread,
+/// `SharedNodeTable` for threads walking the same tree.
+trait NodeStore {
+    fn symmetric(&self) -> bool;
+    /// Statistics of the node stored under the key.
+    fn stats(&self, key: u64) -> Option<MCTSNode>;
+    /// Apply f to the node stored under the key, adding it first if needed.
+    fn update(&mut self, key: u64, f: impl FnOnce(&mut MCTSNode));
+    /// Number of nodes.
+    fn size(&self) -> usize;
+
+    /// The key under which the node of state s is stored.
+    fn key<S: SearchState>(&self, s: &S) -> u64 {
+        if self.symmetric() {
+            s.symmetric_key()
+        } else {
+            s.key()
+        }
+    }
+
+    fn node<S: SearchState>(&self, s: &S) -> Option<MCTSNode> {
+        self.stats(self.key(s))
+    }
+
+    /// Add a node for state s if it has none yet.
+    fn insert<S: SearchState>(&mut self, s: &S) {
+        let key = self.key(s);
+        self.update(key, |_| {});
+    }
+}
+
+impl NodeStore for NodeTable {
+    fn symmetric(&self) -> bool {
+        self.symmetric
+    }
+
+    fn stats(&self, key: u64) -> Option<MCTSNode> {
+        self.nodes.get(&key).copied()
+    }
+
+    fn update(&mut self, key: u64, f: impl FnOnce(&mut MCTSNode)) {
+        f(self.nodes.entry(key).or_default());
+    }
+
+    fn size(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+impl NodeTable {
+    fn new(symmetric: bool) -> Self {
+        NodeTable { nodes: HashMap::default(), symmetric }
+    }
+
+    fn get<S: SearchState>(&self, s: &S) -> Option<&MCTSNode> {
+        self.nodes.get(&self.key(s))
+    }
+
+    /// Add the statistics gathered by the trees of a root-parallel search,
+    /// each holding the nodes it changed from this table.
+    fn merge(&mut self, mut trees: Vec<HashMap<u64, MCTSNode, BuildZobristHasher>>) {
+        // what each tree added, before the table changes
+        for tree in &mut trees {
+            for (key, node) in tree.iter_mut() {
+                let start = self.nodes.get(key).copied().unwrap_or_default();
+                node.visits -= start.visits;
+                node.x_wins -= start.x_wins;
+                node.o_wins -= start.o_wins;
+                node.iterations -= start.iterations;
+            }
+        }
+        for tree in trees {
+            for (key, added) in tree {
+                let merged = self.nodes.entry(key).or_default();
+                merged.visits += added.visits;
+                merged.x_wins += added.x_wins;
+                merged.o_wins += added.o_wins;
+                merged.iterations += added.iterations;
+            }
+        }
+    }
+
+    /// Keep only the nodes of the states reachable from the root through
+    /// states of the table.
+    fn prune<S: SearchState>(&mut self, root: &S) {
+        let mut kept: HashMap<u64, MCTSNode, BuildZobristHasher> = HashMap::default();
+        let mut stack = vec![*root];
+        while let Some(s) = stack.pop() {
+            let key = self.key(&s);
+            let Some(node) = self.nodes.remove(&key) else {
+                // not in the table, or already kept
+                continue;
             };
-            len += 1;
+            kept.insert(key, node);
+            let (moves, n) = s.unique_moves();
+            stack.extend(moves[..n].iter().map(|&m| s.play(m).expect("unique moves are legal")));
+        }
+        self.nodes = kept;
+    }
+}
+
+/// The table of one tree of a root-parallel search: the nodes the tree
+/// changed, over the table the search started from, which all the trees
+/// read without copying it.
+struct TreeTable<'a> {
+    base: &'a NodeTable,
+    nodes: HashMap<u64, MCTSNode, BuildZobristHasher>,
+    /// Nodes added that are not in the base table.
+    added: usize,
+}
+
+impl<'a> TreeTable<'a> {
+    fn new(base: &'a NodeTable) -> Self {
+        TreeTable { base, nodes: HashMap::default(), added: 0 }
+    }
+}
+
+impl NodeStore for TreeTable<'_> {
+    fn symmetric(&self) -> bool {
+        self.base.symmetric
+    }
+
+    fn stats(&self, key: u64) -> Option<MCTSNode> {
+        self.nodes.get(&key).or_else(|| self.base.nodes.get(&key)).copied()
+    }
+
+    fn update(&mut self, key: u64, f: impl FnOnce(&mut MCTSNode)) {
+        let node = self.nodes.entry(key).or_insert_with(|| {
+            let start = self.base.nodes.get(&key).copied();
+            if start.is_none() {
+                self.added += 1;
+            }
+            start.unwrap_or_default()
         });
-    if n_visits > 1 {
-        for i in 0..len {
-            scores[i] = scores[i] * (2.0 * (n_visits as f64).log10()).sqrt();
+        f(node);
+    }
+
+    fn size(&self) -> usize {
+        self.base.size() + self.added
+    }
+}
+
+/// Shards of a `SharedNodeTable`, picked by the top bits of the keys.
+const SHARDS: usize = 64;
+
+fn shard_index(key: u64) -> usize {
+    (key >> (64 - SHARDS.trailing_zeros())) as usize
+}
+
+/// A node table shared by the threads of a tree-parallel search. The nodes
+/// are spread over shards locked separately, so that threads updating
+/// different states rarely wait for each other.
+struct SharedNodeTable {
+    shards: Vec<Mutex<HashMap<u64, MCTSNode, BuildZobristHasher>>>,
+    symmetric: bool,
+    len: AtomicUsize,
+}
+
+impl SharedNodeTable {
+    fn shard(&self, key: u64) -> &Mutex<HashMap<u64, MCTSNode, BuildZobristHasher>> {
+        &self.shards[shard_index(key)]
+    }
+
+    fn into_table(self) -> NodeTable {
+        let mut nodes: HashMap<u64, MCTSNode, BuildZobristHasher> = HashMap::default();
+        for shard in self.shards {
+            nodes.extend(shard.into_inner().expect("shard lock poisoned"));
+        }
+        NodeTable { nodes, symmetric: self.symmetric }
+    }
+}
+
+impl From<NodeTable> for SharedNodeTable {
+    fn from(table: NodeTable) -> Self {
+        let mut shared = SharedNodeTable {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            symmetric: table.symmetric,
+            len: AtomicUsize::new(table.nodes.len()),
+        };
+        for (key, node) in table.nodes {
+            shared.shards[shard_index(key)].get_mut().expect("shard lock poisoned").insert(key, node);
         }
+        shared
+    }
+}
+
+/// Every thread holds a shared reference.
+impl NodeStore for &SharedNodeTable {
+    fn symmetric(&self) -> bool {
+        self.symmetric
+    }
+
+    fn stats(&self, key: u64) -> Option<MCTSNode> {
+        self.shard(key).lock().expect("shard lock poisoned").get(&key).copied()
+    }
+
+    fn update(&mut self, key: u64, f: impl FnOnce(&mut MCTSNode)) {
+        let mut shard = self.shard(key).lock().expect("shard lock poisoned");
+        let node = shard.entry(key).or_insert_with(|| {
+            self.len.fetch_add(1, Ordering::Relaxed);
+            MCTSNode::new()
+        });
+        f(node);
+    }
+
+    fn size(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
     }
-    (scores, len)
 }
 
-fn simulation(current_state: GameState, n: u32) -> Result {
-    if let Some(p ) = winner(&current_state.board) {
+fn find_child_states<S: SearchState>(current_state: S, available_moves: &[Move]) -> ([S; MAX_MOVES], usize) {
+    let mut child_states = [current_state; MAX_MOVES];
+    let mut len = 0;
+    for &m in available_moves.iter() {
+        child_states[len] = current_state.play(m).unwrap();
+        len += 1;
+    }
+    (child_states, len)
+}
+
+/// Scores of the children of the parent state under the selection formula
+/// of the config, the search descending into the best one.
+fn selection_scores<S: SearchState>(node_table: &impl NodeStore, config: &MctsConfig, parent_state: S, child_states: &[S]) -> ([f64; MAX_MOVES], usize) {
+    let mut scores: [f64; MAX_MOVES] = [0.0; MAX_MOVES];
+    let side = parent_state.side();
+    let parent_iterations = node_table.node(&parent_state).map_or(0, |n| n.iterations).max(1) as f64;
+    let c = config.exploration;
+    // PUCT without a policy: every move gets the same prior
+    let prior = 1.0 / child_states.len() as f64;
+    for (score, child) in scores.iter_mut().zip(child_states) {
+        let node = node_table.node(child).unwrap_or_default();
+        let iterations = node.iterations as f64;
+        *score = match config.selection {
+            Selection::Puct => {
+                // unvisited children count as even
+                let value = if node.iterations == 0 { 0.5 } else { node.value(side) };
+                value + c * prior * parent_iterations.sqrt() / (1.0 + iterations)
+            }
+            _ if node.iterations == 0 => f64::INFINITY,
+            Selection::Ucb1 => node.value(side) + c * (parent_iterations.ln() / iterations).sqrt(),
+            Selection::Ucb1Tuned => {
+                let value = node.value(side);
+                let log = parent_iterations.ln() / iterations;
+                let variance = node.square_value(side) - value * value + (2.0 * log).sqrt();
+                value + c / std::f64::consts::SQRT_2 * (log * variance.min(0.25)).sqrt()
+            }
+        };
+    }
+    (scores, child_states.len())
+}
+
+/// Function playing the rollouts of the search: n games from the state
+/// under the given rules.
+trait Rollouts<S>: Fn(&S, u32, Rules) -> Result + Sync {}
+impl<S, F: Fn(&S, u32, Rules) -> Result + Sync> Rollouts<S> for F {}
+
+fn simulation<S: SearchState>(current_state: S, n: u32, rules: Rules, rollouts: &impl Rollouts<S>) -> Result {
+    if let Some(p) = current_state.winner(rules) {
         // println!("rolling out from a winning state");
         return match p {
             Player::X => Result { wins_x: n, wins_o: 0, draws: 0, total: n },
             Player::O => Result { wins_x: 0, wins_o: n, draws: 0, total: n }
         }
     }
-    parallel_simulation(current_state.board, current_state.player, n)
+    rollouts(&current_state, n, rules)
 }
 
-fn one_search(
-    node_table: &mut NodeTable,
-    root_state: GameState,
-    num_simulations: u32
+/// Walk down the tree from the root, play the rollouts of the leaf reached
+/// and add their results to the states on the way. Plies and repetitions
+/// are counted from the start of the game, whose history led to the root,
+/// and the rollouts only get the plies left before the move limit.
+/// With a virtual loss,
+/// each state the walk moves to counts as one more iteration with that many
+/// extra lost rollouts for the side moving to it until the results are
+/// added, so that other threads walking the tree meanwhile spread out.
+fn one_search<S: SearchState>(
+    node_table: &mut impl NodeStore,
+    root_state: S,
+    history: &History,
+    config: &MctsConfig,
+    rules: Rules,
+    rollouts: &impl Rollouts<S>,
+    virtual_loss: u32,
 ) {
+    let draw_rules = rules.draw;
     let mut current_state = root_state;
-    let mut traversed_states: HashSet<GameState> = HashSet::new();
+    let mut plies = history.plies();
+    let mut traversed_states: HashSet<u64, BuildZobristHasher> = HashSet::default();
+    // states in traversal order, to count repetitions
+    let mut path: Vec<S> = Vec::new();
+    // states given a virtual loss, with the side it counts as a win for
+    let mut virtual_wins: Vec<(u64, Player)> = Vec::new();
+    let mut drawn = false;
     loop {
-        traversed_states.insert(current_state);
-        if let None = node_table.get(&current_state) {
-            node_table.insert(current_state, MCTSNode::new());
-        }
-        if let Some(_) = winner(&current_state.board) {
+        traversed_states.insert(node_table.key(&current_state));
+        path.push(current_state);
+        node_table.insert(&current_state);
+        if current_state.winner(rules).is_some() {
             // end traversal if a player has already won
             break;
         }
-        let available_moves = find_available_moves(&current_state.board, current_state.player);
+        let repetitions = history.occurrences(current_state.key()) + path.iter().filter(|&&s| s == current_state).count() as u32;
+        if draw_rules.is_draw(plies, repetitions) {
+            // end traversal if the game is drawn
+            drawn = true;
+            break;
+        }
+        let available_moves = current_state.unique_moves();
         if available_moves.1 == 0 {
             // end traversal if there are not moves available
             break;
         }
         let child_states = find_child_states(current_state, &available_moves.0[0..available_moves.1]);
-        let scores = calculate_ucb_scores(node_table, current_state, &child_states.0[0..child_states.1]);
+        let scores = selection_scores(node_table, config, current_state, &child_states.0[0..child_states.1]);
         // assert!(scores.1 == child_states.1);
-        let (max_score_i, &max_score) = scores.0[0..scores.1].iter()
+        let (max_score_i, _) = scores.0[0..scores.1].iter()
             .enumerate()
             .max_by(|a, b| a.1.total_cmp(b.1))
             .unwrap();
         // println!("scores {:?}", scores);
         // println!("chose {} with score {}", max_score_i, max_score);
-        if max_score == std::f64::INFINITY {
+        let child_key = node_table.key(&child_states.0[max_score_i]);
+        let unvisited = node_table.stats(child_key).is_none_or(|n| n.iterations == 0);
+        if virtual_loss > 0 && !traversed_states.contains(&child_key) {
+            let winner = current_state.side().next();
+            node_table.update(child_key, |n| {
+                n.visits += virtual_loss;
+                *n.wins_mut(winner) += virtual_loss;
+                n.iterations += 1;
+            });
+            virtual_wins.push((child_key, winner));
+        }
+        if unvisited {
             // end traversal if a child wasn't visited
             // add it to the node table and traversed node set
             current_state = child_states.0[max_score_i];
-            traversed_states.insert(current_state);
-            node_table.insert(current_state, MCTSNode::new());
+            plies += 1;
+            traversed_states.insert(node_table.key(&current_state));
+            node_table.insert(&current_state);
             break;
         }
-        if traversed_states.contains(&child_states.0[max_score_i]) {
-            // end traversal in case of a loop
+        if draw_rules.repetitions.is_none() && traversed_states.contains(&child_key) {
+            // end traversal in case of a loop, unless repetitions draw the game
             break;
         }
         current_state = child_states.0[max_score_i];
+        plies += 1;
     }
     // println!("traversal ended at level {}", level);
-    let result = simulation(current_state, num_simulations);
+    let n = config.rollouts_per_leaf;
+    let result = if drawn {
+        Result { wins_x: 0, wins_o: 0, draws: n, total: n }
+    } else {
+        let draw = DrawRules { max_plies: draw_rules.max_plies.map(|m| m.saturating_sub(plies)), ..draw_rules };
+        simulation(current_state, n, Rules { draw, ..rules }, rollouts)
+    };
     // assert!(result.total == 1000);
-    for state in traversed_states {
-        let node = node_table.get_mut(&state).expect("visited node not found in table");
-        node.visits += result.total;
-        node.x_wins += result.wins_x;
-        node.o_wins += result.wins_o;
+    for key in traversed_states {
+        node_table.update(key, |node| {
+            node.visits += result.total;
+            node.x_wins += result.wins_x;
+            node.o_wins += result.wins_o;
+            node.iterations += 1;
+        });
+    }
+    for (key, winner) in virtual_wins {
+        node_table.update(key, |node| {
+            node.visits -= virtual_loss;
+            *node.wins_mut(winner) -= virtual_loss;
+            node.iterations -= 1;
+        });
     }
 }
 
-fn best_move(
-    node_table: &NodeTable,
-    root_state: GameState
+/// The most visited child of the root and the visited child with the best
+/// value, if any.
+fn final_candidates<S: SearchState>(node_table: &impl NodeStore, root_state: S) -> (Option<Move>, Option<Move>) {
+    let (moves, n) = root_state.unique_moves();
+    let side = root_state.side();
+    let node = |m: &Move| node_table.node(&root_state.play(*m).unwrap()).unwrap_or_default();
+    let most_visited = moves[..n].iter().copied().max_by_key(|m| node(m).visits);
+    let best_value = moves[..n].iter().copied()
+        .filter(|m| node(m).visits > 0)
+        .max_by(|m1, m2| node(m1).value(side).total_cmp(&node(m2).value(side)));
+    (most_visited, best_value)
+}
+
+fn best_move<S: SearchState>(
+    node_table: &impl NodeStore,
+    root_state: S,
+    final_move: FinalMove,
 ) -> Option<Move> {
-    let available_moves = find_available_moves(&root_state.board, root_state.player);
-    available_moves.0
-        .into_iter()
-        .take(available_moves.1)
-        .max_by(|m1, m2| {
-            let state1 = m1.apply(root_state.player, &root_state.board).unwrap();
-            let state2 = m2.apply(root_state.player, &root_state.board).unwrap();
-            let node1 = node_table.get(&GameState { board: state1, player: root_state.player.next() });
-            let node2 = node_table.get(&GameState { board: state2, player: root_state.player.next() });
-            let visits = |n: Option<&MCTSNode>| -> u32 {
-                match n {
-                    Some(n) => n.visits,
-                    None => 0
-                }
-            };
-            // println!("node: {}, visits: {}, x_wins: {}; node: {}, visits: {}, x_wins: {}", m1, visits(node1), node1.unwrap().x_wins, m2, visits(node2), node2.unwrap().x_wins);
-            visits(node1).cmp(&visits(node2))
-        })
+    let (most_visited, best_value) = final_candidates(node_table, root_state);
+    match final_move {
+        FinalMove::MostVisits | FinalMove::RobustMax => most_visited,
+        FinalMove::BestValue => best_value.or(most_visited),
+    }
 }
 
-pub fn mcts(
-    root: GameState,
+/// Whether the most visited child of the root also has the best value, as
+/// robust-max waits for.
+fn robust<S: SearchState>(node_table: &impl NodeStore, root_state: S) -> bool {
+    let side = root_state.side();
+    let value = |m: Option<Move>| m.and_then(|m| node_table.node(&root_state.play(m).unwrap())).map(|n| n.value(side));
+    let (most_visited, best_value) = final_candidates(node_table, root_state);
+    value(most_visited) >= value(best_value)
+}
+
+/// Search the best move of the side to move in the root state with
+/// `iterations` iterations of MCTS, set up by the config. Once the token is
+/// cancelled, the search stops after the current iteration and returns the
+/// best move found so far.
+pub fn mcts<S: SearchState>(
+    root: S,
     iterations: u32,
-    sim_per_iter: u32,
-    progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>
+    rules: Rules,
+    config: MctsConfig,
+    progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>,
+    cancel: Option<&CancelToken>,
+) -> Option<Move> {
+    Mcts::new(root, rules, config).search(iterations, progress_channel, cancel)
+}
+
+/// Same as `mcts`, searching until the budget is spent instead of for a
+/// number of iterations.
+pub fn mcts_timed<S: SearchState>(
+    root: S,
+    budget: TimeBudget,
+    rules: Rules,
+    config: MctsConfig,
+    progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>,
+    cancel: Option<&CancelToken>,
 ) -> Option<Move> {
-    let mut node_table: NodeTable = HashMap::new();
-    node_table.insert(root, MCTSNode::new());
+    Mcts::new(root, rules, config).search_timed(budget, progress_channel, cancel)
+}
+
+/// When a timed search stops: at the deadline, or once it has added
+/// `max_nodes` states to the table, whichever comes first. The states kept
+/// from earlier calls of a persistent search do not count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBudget {
+    pub deadline: Instant,
+    pub max_nodes: Option<usize>,
+}
+
+impl TimeBudget {
+    /// A budget of the given time from now, without node limit.
+    pub fn from_now(time: Duration) -> Self {
+        TimeBudget { deadline: Instant::now() + time, max_nodes: None }
+    }
+
+    pub fn with_max_nodes(self, max_nodes: usize) -> Self {
+        TimeBudget { max_nodes: Some(max_nodes), ..self }
+    }
+
+    /// Whether a search that added `nodes` states to its table must stop.
+    fn spent(&self, nodes: usize) -> bool {
+        self.max_nodes.is_some_and(|m| nodes >= m) || Instant::now() >= self.deadline
+    }
+}
+
+/// A search that keeps its statistics from one call to the next: after the
+/// root moves on to the state actually played, the statistics gathered for
+/// the states still reachable from it are reused, and the others dropped.
+#[derive(Debug)]
+pub struct Mcts<S: SearchState = GameState> {
+    node_table: NodeTable,
+    root: S,
+    /// History of the game before the root.
+    history: History,
+    rules: Rules,
+    config: MctsConfig,
+}
+
+impl<S: SearchState> Mcts<S> {
+    /// A search from the root with no statistics yet, the game starting at
+    /// the root.
+    pub fn new(root: S, rules: Rules, config: MctsConfig) -> Self {
+        Self::with_history(root, History::new(), rules, config)
+    }
+
+    /// A search from the root reached after the history, with no statistics
+    /// yet.
+    pub fn with_history(root: S, history: History, rules: Rules, config: MctsConfig) -> Self {
+        let mut node_table = NodeTable::new(config.symmetric);
+        node_table.insert(&root);
+        Mcts { node_table, root, history, rules, config }
+    }
+
+    pub fn root(&self) -> &S {
+        &self.root
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.rules
+    }
+
+    pub fn config(&self) -> &MctsConfig {
+        &self.config
+    }
+
+    /// Number of states with statistics.
+    pub fn len(&self) -> usize {
+        self.node_table.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node_table.nodes.is_empty()
+    }
+
+    /// Statistics of the state, if the search visited it.
+    pub fn node(&self, s: &S) -> Option<&MCTSNode> {
+        self.node_table.get(s)
+    }
 
-    for i in 0..iterations {
-        one_search(&mut node_table, root, sim_per_iter);
-        if let Some(c) = &progress_channel && i % 10 == 0 {
-            let _ = c.send((i, best_move(&node_table, root)));
+    /// Run more iterations from the root, or fewer if the token is
+    /// cancelled, and return the best move found with all the statistics
+    /// gathered so far.
+    pub fn search(
+        &mut self,
+        iterations: u32,
+        progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>,
+        cancel: Option<&CancelToken>,
+    ) -> Option<Move> {
+        let control = Control { iterations: Some(iterations), budget: None, cancel, progress_channel };
+        run_mcts(&mut self.node_table, self.root, &self.history, &self.config, self.rules, &S::rollouts, control)
+    }
+
+    /// Run iterations from the root until the budget is spent or the token
+    /// is cancelled, and return the best move found so far. The budget is
+    /// checked between iterations, and at least one iteration is run.
+    pub fn search_timed(
+        &mut self,
+        budget: TimeBudget,
+        progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>,
+        cancel: Option<&CancelToken>,
+    ) -> Option<Move> {
+        let control = Control { iterations: None, budget: Some(budget), cancel, progress_channel };
+        run_mcts(&mut self.node_table, self.root, &self.history, &self.config, self.rules, &S::rollouts, control)
+    }
+
+    /// Move the root to the state reached by playing m.
+    pub fn advance(&mut self, m: Move) -> std::result::Result<(), GameError> {
+        let root = self.root.play(m)?;
+        let mut history = std::mem::take(&mut self.history);
+        history.push(self.root.key());
+        self.set_root(root, history);
+        Ok(())
+    }
+
+    /// Move the root to any state reached after the history, e.g. after
+    /// several moves or an undo, dropping the statistics of the states not
+    /// reachable from it.
+    pub fn set_root(&mut self, root: S, history: History) {
+        self.node_table.prune(&root);
+        self.node_table.insert(&root);
+        self.root = root;
+        self.history = history;
+    }
+}
+
+/// Same as `mcts`, with rollouts stopped after `cutoff.plies` plies and
+/// decided by the evaluator of the cutoff.
+pub fn mcts_with_cutoff<const N: usize, E: Evaluator<N> + Sync>(
+    root: GameState<N>,
+    iterations: u32,
+    rules: Rules,
+    config: MctsConfig,
+    cutoff: &RolloutCutoff<E>,
+    progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>,
+    cancel: Option<&CancelToken>,
+) -> Option<Move> {
+    let rollouts = |s: &GameState<N>, n, rules| cutoff.rollouts(*s, n, rules);
+    let mut node_table = NodeTable::new(config.symmetric);
+    node_table.insert(&root);
+    let control = Control { iterations: Some(iterations), budget: None, cancel, progress_channel };
+    run_mcts(&mut node_table, root, &History::new(), &config, rules, &rollouts, control)
+}
+
+/// When `run_mcts` stops, and where it reports its progress.
+struct Control<'a> {
+    /// Number of iterations to run, robust-max possibly adding more.
+    iterations: Option<u32>,
+    budget: Option<TimeBudget>,
+    cancel: Option<&'a CancelToken>,
+    progress_channel: Option<mpsc::Sender<(u32, Option<Move>)>>,
+}
+
+impl Control<'_> {
+    /// Whether to stop before iteration i, with the given number of nodes
+    /// added by the search.
+    fn stop(&self, i: u32, nodes: usize) -> bool {
+        self.iterations.is_some_and(|n| i >= n) || self.interrupted(i, nodes)
+    }
+
+    /// Whether the budget is spent or the token cancelled, once an
+    /// iteration has been run.
+    fn interrupted(&self, i: u32, nodes: usize) -> bool {
+        i > 0 && (self.budget.is_some_and(|b| b.spent(nodes)) || self.cancel.is_some_and(CancelToken::is_cancelled))
+    }
+
+    fn report(&self, i: u32, best_move: impl FnOnce() -> Option<Move>) {
+        if let Some(c) = &self.progress_channel && i.is_multiple_of(10) {
+            let _ = c.send((i, best_move()));
+        }
+    }
+}
+
+/// Run iterations until the control tells to stop, with the parallelism of
+/// the config. Robust-max then runs up to a tenth more iterations, until the
+/// most visited child has the best value too, within the budget.
+fn run_mcts<S: SearchState>(
+    node_table: &mut NodeTable,
+    root: S,
+    history: &History,
+    config: &MctsConfig,
+    rules: Rules,
+    rollouts: &impl Rollouts<S>,
+    control: Control,
+) -> Option<Move> {
+    // nodes kept from earlier searches
+    let kept = node_table.size();
+    let mut i = match config.parallelism {
+        Parallelism::Leaf => {
+            let mut i = 0;
+            while !control.stop(i, node_table.size() - kept) {
+                one_search(node_table, root, history, config, rules, rollouts, 0);
+                control.report(i, || best_move(node_table, root, config.final_move));
+                i += 1;
+            }
+            i
+        }
+        Parallelism::Tree { threads, virtual_loss } => {
+            let shared = SharedNodeTable::from(std::mem::take(node_table));
+            let count = AtomicU32::new(0);
+            thread::scope(|scope| {
+                for _ in 0..threads.max(1) {
+                    scope.spawn(|| {
+                        let mut store = &shared;
+                        loop {
+                            let i = count.fetch_add(1, Ordering::Relaxed);
+                            if control.stop(i, store.size() - kept) {
+                                break;
+                            }
+                            one_search(&mut store, root, history, config, rules, rollouts, virtual_loss);
+                            control.report(i, || best_move(&store, root, config.final_move));
+                        }
+                    });
+                }
+            });
+            *node_table = shared.into_table();
+            // every thread drew one number past the last iteration
+            count.into_inner() - threads.max(1) as u32
         }
-    } 
-    best_move(&node_table, root)
+        Parallelism::Root { threads } => {
+            let count = AtomicU32::new(0);
+            // nodes added by all the trees, a state added by several trees
+            // counting once per tree
+            let added = AtomicUsize::new(0);
+            let (count_ref, added_ref, control) = (&count, &added, &control);
+            let base = &*node_table;
+            let trees: Vec<_> = thread::scope(|scope| {
+                let handles: Vec<_> = (0..threads.max(1)).map(|t| {
+                    let mut tree = TreeTable::new(base);
+                    scope.spawn(move || {
+                        loop {
+                            let i = count_ref.fetch_add(1, Ordering::Relaxed);
+                            if control.stop(i, added_ref.load(Ordering::Relaxed)) {
+                                break;
+                            }
+                            let size = tree.size();
+                            one_search(&mut tree, root, history, config, rules, rollouts, 0);
+                            added_ref.fetch_add(tree.size() - size, Ordering::Relaxed);
+                            if t == 0 {
+                                control.report(i, || best_move(&tree, root, config.final_move));
+                            }
+                        }
+                        tree.nodes
+                    })
+                }).collect();
+                handles.into_iter().map(|h| h.join().expect("search thread panicked")).collect()
+            });
+            node_table.merge(trees);
+            count.into_inner() - threads.max(1) as u32
+        }
+    };
+    if config.final_move == FinalMove::RobustMax {
+        let last = i + i / 10;
+        while i < last && !robust(node_table, root) && !control.interrupted(i, node_table.size() - kept) {
+            one_search(node_table, root, history, config, rules, rollouts, 0);
+            i += 1;
+        }
+    }
+    best_move(node_table, root, config.final_move)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::game::{Board, Player, Move, Shift};
+    use crate::game::{ALLOWED_MOVES, DrawRules, Move, Shift};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
     use crate::simulations::Result;
-    const B: Board = [
-        [Some(Player::X), Some(Player::X), Some(Player::X), None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [None, Some(Player::X), None, None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [Some(Player::X), None, Some(Player::O), None, None],
-    ];
-    const B_WON: Board = [
-        [Some(Player::X), Some(Player::X), Some(Player::X), None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [None, Some(Player::X), None, None, None],
-        [Some(Player::O), Some(Player::X), None, None, None],
-        [Some(Player::X), Some(Player::X), Some(Player::O), None, None],
-    ];
+    use crate::testing::{config, state, MIDGAME, MIDGAME_WON};
 
     #[test]
     fn test_mcts_x_almost_won() {
-        let b = B;
-        let p = Player::X;
-        let root = GameState { board: b, player: p };
-        let best_move = mcts(root, 100, 1000, None);
-        let winning_move = vec![
+        let root = state(MIDGAME);
+        let best_move = mcts(root, 100, Rules::default(), config(1000, false), None, None);
+        let winning_move = [
             Move{x: 1, y: 4, shift: Shift::TOP},
             Move{x: 1, y: 4, shift: Shift::LEFT},
             Move{x: 3, y: 4, shift: Shift::LEFT},
@@ -212,39 +1074,495 @@ mod tests {
 
     #[test]
     fn test_simulation() {
-        let b = B_WON;
-        let result = simulation(GameState { board: b, player: Player::O }, 2000);
+        let result = simulation(state(MIDGAME_WON), 2000, Rules::default(), &GameState::rollouts);
         assert_eq!(Result{wins_x: 2000, wins_o: 0, draws: 0, total: 2000}, result );
     }
 
     #[test]
     fn test_one_search() {
-        let mut node_table = NodeTable::new();
+        let mut node_table = NodeTable::new(false);
         for _ in 0..44 {
-            one_search(&mut node_table, GameState { board: B, player: Player::X }, 1000);
+            one_search(&mut node_table, state(MIDGAME), &History::new(), &config(1000, false), Rules::default(), &GameState::rollouts, 0);
         }
-        let winning_state = GameState{board: B_WON, player: Player::O};
+        let winning_state = state(MIDGAME_WON);
         let winning_node = node_table.get(&winning_state).expect("winning state not in node table");
         assert!(winning_node.x_wins >= 1000);
         assert!(winning_node.o_wins == 0);
         assert!(winning_node.visits == winning_node.x_wins);
     }
 
+    #[test]
+    fn test_one_search_draw() {
+        let mut node_table = NodeTable::new(false);
+        let root = state("5/5/5/5/5 X");
+        let rules = Rules { draw: DrawRules { repetitions: None, max_plies: Some(0) }, ..Rules::default() };
+        one_search(&mut node_table, root, &History::new(), &config(100, false), rules, &GameState::rollouts, 0);
+        let root_node = node_table.get(&root).unwrap();
+        assert_eq!(root_node.visits, 100);
+        assert_eq!(root_node.x_wins + root_node.o_wins, 0);
+        assert_eq!(node_table.nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_one_search_history() {
+        let root = state("5/5/5/5/5 X");
+        // one ply before the move limit, the rollouts of the children are drawn
+        let rules = Rules { draw: DrawRules { repetitions: None, max_plies: Some(200) }, ..Rules::default() };
+        let mut history = History::new();
+        for _ in 0..199 {
+            history.push(0);
+        }
+        let mut node_table = NodeTable::new(false);
+        for _ in 0..10 {
+            one_search(&mut node_table, root, &history, &config(10, false), rules, &GameState::rollouts, 0);
+        }
+        let root_node = node_table.get(&root).unwrap();
+        assert_eq!((root_node.visits, root_node.draws()), (100, 100));
+        // the root occurred twice before, threefold repetition draws it
+        let mut history = History::new();
+        history.push(root.key());
+        history.push(1);
+        history.push(root.key());
+        history.push(2);
+        let mut node_table = NodeTable::new(false);
+        one_search(&mut node_table, root, &history, &config(10, false), Rules::default(), &GameState::rollouts, 0);
+        assert_eq!(node_table.get(&root).unwrap().draws(), 10);
+        assert_eq!(node_table.nodes.len(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let root = state(MIDGAME);
+        let json = serde_json::to_string(&root).unwrap();
+        assert_eq!(serde_json::from_str::<GameState>(&json).unwrap(), root);
+        let node = MCTSNode { visits: 10, x_wins: 7, o_wins: 2, iterations: 1 };
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(json, r#"{"visits":10,"x_wins":7,"o_wins":2,"iterations":1}"#);
+        assert_eq!(serde_json::from_str::<MCTSNode>(&json).unwrap(), node);
+        let result = Result { wins_x: 1, wins_o: 2, draws: 3, total: 6 };
+        let json = serde_json::to_string(&result).unwrap();
+        assert_eq!(serde_json::from_str::<Result>(&json).unwrap(), result);
+    }
+
+    #[test]
+    fn test_one_search_symmetric() {
+        let root = state("5/5/5/5/5 X");
+        let mut node_table = NodeTable::new(false);
+        let mut symmetric_table = NodeTable::new(true);
+        // the first tile ends up on one of the 16 border cells, that is in a
+        // corner, next to a corner or in the middle of a side
+        for _ in 0..16 {
+            one_search(&mut node_table, root, &History::new(), &config(10, false), Rules::default(), &GameState::rollouts, 0);
+        }
+        for _ in 0..3 {
+            one_search(&mut symmetric_table, root, &History::new(), &config(10, false), Rules::default(), &GameState::rollouts, 0);
+        }
+        assert_eq!(node_table.nodes.len(), 17);
+        assert_eq!(symmetric_table.nodes.len(), 4);
+        let corner = symmetric_table.get(&state("4X/5/5/5/5 O")).unwrap();
+        assert_eq!(corner, symmetric_table.get(&state("5/5/5/5/X4 O")).unwrap());
+        assert_eq!(corner.visits, 10);
+    }
+
+    #[test]
+    fn test_mcts_symmetric_x_almost_won() {
+        let root = state(MIDGAME);
+        let best_move = mcts(root, 100, Rules::default(), config(200, true), None, None).unwrap();
+        let (_, winner) = best_move.outcome(root.player, &root.board, Rules::default()).unwrap();
+        assert_eq!(winner, Some(Player::X));
+    }
+
+    #[test]
+    fn test_mcts_other_sizes() {
+        // X completes the top row of the 3x3 board
+        let root: GameState<3> = "X1X/O2/O2 X".parse().unwrap();
+        let m = mcts(root, 50, Rules::default(), config(50, true), None, None).unwrap();
+        assert_eq!(m.outcome(root.player, &root.board, Rules::default()).unwrap().1, Some(Player::X));
+        let root: GameState<6> = "XXXXX1/O5/O5/O5/6/6 X".parse().unwrap();
+        let m = mcts(root, 100, Rules::default(), config(100, false), None, None).unwrap();
+        assert_eq!(m.outcome(root.player, &root.board, Rules::default()).unwrap().1, Some(Player::X));
+    }
+
+    #[test]
+    fn test_persistent_search() {
+        let mut search = Mcts::new(state(MIDGAME), Rules::default(), config(10, true));
+        let m = search.search(100, None, None).unwrap();
+        let size = search.len();
+        let child = *search.node(&state(MIDGAME).play(m).unwrap()).unwrap();
+        assert!(child.visits > 0);
+        search.advance(m).unwrap();
+        assert_eq!(search.history().occurrences(state(MIDGAME).key()), 1);
+        // the statistics of the new root are kept, those of its siblings dropped
+        assert_eq!(search.node(search.root()), Some(&child));
+        assert!(search.len() < size);
+        search.set_root(state("5/5/5/5/5 X"), History::new());
+        assert_eq!(search.len(), 1);
+        assert_eq!(search.node(search.root()).unwrap().visits, 0);
+        search.search(10, None, None);
+        assert_eq!(search.node(search.root()).unwrap().visits, 100);
+    }
+
+    #[test]
+    fn test_prune_keeps_reachable_states() {
+        let root = state("XO3/5/5/5/5 X");
+        let mut search = Mcts::new(root, Rules::default(), config(5, false));
+        search.search(300, None, None);
+        let m = "e1<".parse().unwrap();
+        let next = root.play(m).unwrap();
+        let size = search.len();
+        search.advance(m).unwrap();
+        // tiles are never removed, so states with a single X tile are not
+        // reachable any more
+        assert!(search.len() < size);
+        assert!(search.node(&root).is_none());
+        let before = search.node(&next).unwrap().visits;
+        search.search(50, None, None);
+        assert_eq!(search.node(&next).unwrap().visits, before + 250);
+    }
+
+    #[test]
+    fn test_mcts_timed() {
+        let start = Instant::now();
+        let m = mcts_timed(state("5/5/5/5/5 X"), TimeBudget::from_now(Duration::from_millis(100)), Rules::default(), config(100, true), None, None);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(m.is_some());
+        let root = state(MIDGAME);
+        let budget = TimeBudget::from_now(Duration::from_secs(60)).with_max_nodes(100);
+        let m = mcts_timed(root, budget, Rules::default(), config(100, true), None, None).unwrap();
+        assert_eq!(m.outcome(root.player, &root.board, Rules::default()).unwrap().1, Some(Player::X));
+        // an expired budget still runs one iteration and returns a move
+        let past = TimeBudget { deadline: start, max_nodes: None };
+        let mut search = Mcts::new(state("5/5/5/5/5 X"), Rules::default(), config(10, false));
+        assert!(search.search_timed(past, None, None).is_some());
+        assert_eq!(search.node(search.root()).unwrap().visits, 10);
+        // the nodes kept from the previous calls do not count
+        let budget = TimeBudget::from_now(Duration::from_secs(60)).with_max_nodes(20);
+        let before = search.len();
+        search.search_timed(budget, None, None);
+        assert_eq!(search.len(), before + 20);
+        search.search_timed(budget, None, None);
+        assert_eq!(search.len(), before + 40);
+    }
+
+    #[test]
+    fn test_mcts_with_cutoff() {
+        let root = state(MIDGAME);
+        let cutoff = RolloutCutoff { plies: 4, evaluator: crate::eval::LineEvaluator::new(Rules::default()) };
+        // the evaluator favors X after most moves, so the search explores less
+        let config = MctsConfig { exploration: 0.3, ..config(50, true) };
+        let m = mcts_with_cutoff(root, 300, Rules::default(), config, &cutoff, None, None).unwrap();
+        assert_eq!(m.outcome(root.player, &root.board, Rules::default()).unwrap().1, Some(Player::X));
+    }
+
+    #[test]
+    fn test_mcts_misere() {
+        // X must not complete the top row
+        let root = state("XXXX1/O4/O4/5/5 X");
+        let misere = Rules { misere: true, ..Rules::default() };
+        for _ in 0..5 {
+            let m = mcts(root, 50, misere, config(50, true), None, None).unwrap();
+            assert_ne!(m.outcome(root.player, &root.board, misere).unwrap().1, Some(Player::O));
+        }
+    }
+
+    #[test]
+    fn test_node_value() {
+        let node = MCTSNode { visits: 10, x_wins: 6, o_wins: 2, iterations: 1 };
+        assert_eq!(node.draws(), 2);
+        assert_eq!(node.value(Player::X), 0.7);
+        assert_eq!(node.value(Player::O), 0.3);
+        assert_eq!(MCTSNode::new().value(Player::X), 0.0);
+    }
+
+    /// A table for the empty board where the first two children have the
+    /// given statistics, X winning `x_wins` of 100 and 50 rollouts played by
+    /// 10 and 5 iterations.
+    fn two_children(x_wins: [u32; 2]) -> (NodeTable, GameState, [Move; 2]) {
+        let root = state("5/5/5/5/5 X");
+        let (moves, _) = root.unique_moves();
+        let mut node_table = NodeTable::new(false);
+        node_table.nodes.insert(root.key(), MCTSNode { visits: 150, x_wins: x_wins[0] + x_wins[1], o_wins: 0, iterations: 15 });
+        for (i, visits) in [100, 50].into_iter().enumerate() {
+            let child = root.play(moves[i]).unwrap();
+            node_table.nodes.insert(child.key(), MCTSNode { visits, x_wins: x_wins[i], o_wins: visits - x_wins[i], iterations: visits / 10 });
+        }
+        (node_table, root, [moves[0], moves[1]])
+    }
+
+    #[test]
+    fn test_selection_scores() {
+        let (node_table, root, [m1, m2]) = two_children([60, 40]);
+        let children = [root.play(m1).unwrap(), root.play(m2).unwrap(), root.play(root.unique_moves().0[2]).unwrap()];
+        let ucb1 = selection_scores(&node_table, &MctsConfig::default(), root, &children).0;
+        // the exploration term counts iterations, not rollouts
+        let expected = 0.6 + std::f64::consts::SQRT_2 * (15f64.ln() / 10.0).sqrt();
+        assert!((ucb1[0] - expected).abs() < 1e-9);
+        assert_eq!(ucb1[2], f64::INFINITY);
+        let tuned = MctsConfig { selection: Selection::Ucb1Tuned, ..MctsConfig::default() };
+        let tuned = selection_scores(&node_table, &tuned, root, &children).0;
+        // the variance of a result is at most 1/4
+        assert!(tuned[0] < ucb1[0] && tuned[1] < ucb1[1] && tuned[0] > 0.6);
+        let puct = MctsConfig { selection: Selection::Puct, exploration: 1.0, ..MctsConfig::default() };
+        let puct = selection_scores(&node_table, &puct, root, &children).0;
+        assert!((puct[1] - (0.8 + 15f64.sqrt() / 3.0 / 6.0)).abs() < 1e-9);
+        assert!(puct[2].is_finite());
+        // the exploration constant trades value for visits
+        let greedy = MctsConfig { exploration: 0.0, ..MctsConfig::default() };
+        let greedy = selection_scores(&node_table, &greedy, root, &children).0;
+        assert_eq!(&greedy[..2], &[0.6, 0.8]);
+    }
+
+    #[test]
+    fn test_robust_max_within_budget() {
+        // the root is not robust, and the other children of the root are
+        // unvisited, so each iteration adds one of them
+        let (mut node_table, root, _) = two_children([60, 40]);
+        let config = MctsConfig { final_move: FinalMove::RobustMax, ..config(1, false) };
+        let budget = TimeBudget::from_now(Duration::from_secs(60)).with_max_nodes(20);
+        let control = Control { iterations: None, budget: Some(budget), cancel: None, progress_channel: None };
+        run_mcts(&mut node_table, root, &History::new(), &config, Rules::default(), &GameState::rollouts, control);
+        // the budget is spent when the search would go on for robust-max
+        assert_eq!(node_table.get(&root).unwrap().iterations, 15 + 20);
+        assert!(!robust(&node_table, root));
+        // searches for a number of iterations still go on
+        let control = Control { iterations: Some(20), budget: None, cancel: None, progress_channel: None };
+        run_mcts(&mut node_table, root, &History::new(), &config, Rules::default(), &GameState::rollouts, control);
+        assert_eq!(node_table.get(&root).unwrap().iterations, 15 + 20 + 22);
+    }
+
+    #[test]
+    fn test_final_move() {
+        let (node_table, root, [m1, m2]) = two_children([60, 40]);
+        assert_eq!(best_move(&node_table, root, FinalMove::MostVisits), Some(m1));
+        assert_eq!(best_move(&node_table, root, FinalMove::BestValue), Some(m2));
+        assert!(!robust(&node_table, root));
+        let (node_table, root, _) = two_children([90, 40]);
+        assert!(robust(&node_table, root));
+        assert_eq!(best_move(&node_table, root, FinalMove::RobustMax), Some(m1));
+    }
+
+    #[test]
+    fn test_mcts_configs() {
+        let root = state(MIDGAME);
+        for selection in [Selection::Ucb1, Selection::Ucb1Tuned, Selection::Puct] {
+            for final_move in [FinalMove::MostVisits, FinalMove::BestValue, FinalMove::RobustMax] {
+                let config = MctsConfig { selection, final_move, ..MctsConfig::default() };
+                let m = mcts(root, 100, Rules::default(), config, None, None).unwrap();
+                let winner = m.outcome(root.player, &root.board, Rules::default()).unwrap().1;
+                assert_eq!(winner, Some(Player::X), "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_node_table() {
+        let root = state(MIDGAME);
+        let mut node_table = NodeTable::new(true);
+        for _ in 0..20 {
+            one_search(&mut node_table, root, &History::new(), &config(10, true), Rules::default(), &GameState::rollouts, 0);
+        }
+        let nodes = node_table.nodes.clone();
+        let shared = SharedNodeTable::from(node_table);
+        let mut store = &shared;
+        assert_eq!(store.size(), nodes.len());
+        assert_eq!(store.node(&root), nodes.get(&root.symmetric_key()).copied());
+        store.insert(&state("5/5/5/5/5 X"));
+        assert_eq!(store.size(), nodes.len() + 1);
+        let table = shared.into_table();
+        assert!(table.symmetric);
+        assert_eq!(table.nodes.len(), nodes.len() + 1);
+        assert!(nodes.iter().all(|(k, n)| table.nodes.get(k) == Some(n)));
+    }
+
+    #[test]
+    fn test_merge_trees() {
+        let root = state(MIDGAME);
+        let mut node_table = NodeTable::new(true);
+        for _ in 0..5 {
+            one_search(&mut node_table, root, &History::new(), &config(10, true), Rules::default(), &GameState::rollouts, 0);
+        }
+        let base = node_table.clone();
+        // the trees only hold the nodes they changed, and read the others
+        // from the base table
+        let trees: Vec<_> = (0..2).map(|_| {
+            let mut tree = TreeTable::new(&base);
+            for _ in 0..5 {
+                one_search(&mut tree, root, &History::new(), &config(10, true), Rules::default(), &GameState::rollouts, 0);
+            }
+            assert!(tree.nodes.len() < tree.size());
+            assert_eq!(tree.size(), base.size() + tree.nodes.keys().filter(|k| !base.nodes.contains_key(k)).count());
+            tree.nodes
+        }).collect();
+        node_table.merge(trees);
+        let root_node = node_table.get(&root).unwrap();
+        assert_eq!((root_node.iterations, root_node.visits), (15, 150));
+        assert!(node_table.nodes.values().all(|n| n.x_wins + n.o_wins <= n.visits));
+    }
+
+    #[test]
+    fn test_virtual_loss_is_taken_back() {
+        let root = state("5/5/5/5/5 X");
+        let mut node_table = NodeTable::new(false);
+        let mut virtual_table = NodeTable::new(false);
+        // every walk goes through the same unvisited children, whatever
+        // the virtual loss, and leaves the same visits behind
+        for _ in 0..16 {
+            one_search(&mut node_table, root, &History::new(), &config(1, false), Rules::default(), &GameState::rollouts, 0);
+            one_search(&mut virtual_table, root, &History::new(), &config(1, false), Rules::default(), &GameState::rollouts, 5);
+        }
+        let visits = |t: &NodeTable| t.nodes.iter().map(|(&k, n)| (k, (n.visits, n.iterations))).collect::<HashMap<_, _>>();
+        assert_eq!(visits(&node_table), visits(&virtual_table));
+        assert!(virtual_table.nodes.values().all(|n| n.x_wins + n.o_wins <= n.visits));
+    }
+
+    #[test]
+    fn test_parallel_searches() {
+        let root = state(MIDGAME);
+        let modes = [
+            Parallelism::Tree { threads: 4, virtual_loss: 3 },
+            Parallelism::Root { threads: 4 },
+        ];
+        for parallelism in modes {
+            let config = MctsConfig { parallelism, ..config(20, true) };
+            let mut search = Mcts::new(root, Rules::default(), config);
+            // enough iterations for each of the root-parallel trees to visit
+            // every move
+            let m = search.search(200, None, None).unwrap();
+            assert_eq!(m.outcome(root.player, &root.board, Rules::default()).unwrap().1, Some(Player::X), "{:?}", parallelism);
+            // no virtual loss is left, and the trees add up to the iterations
+            assert_eq!(search.node(&root).unwrap().visits, 200 * 20);
+            assert_eq!(search.node(&root).unwrap().iterations, 200);
+            search.search(10, None, None);
+            assert_eq!(search.node(&root).unwrap().visits, 210 * 20);
+            assert!(search.node_table.nodes.values().all(|n| n.x_wins + n.o_wins <= n.visits));
+        }
+    }
+
+    #[test]
+    fn test_parallel_timed_search() {
+        let (tx, rx) = mpsc::channel();
+        let config = MctsConfig { parallelism: Parallelism::Tree { threads: 2, virtual_loss: 1 }, ..config(10, true) };
+        let budget = TimeBudget::from_now(Duration::from_secs(60)).with_max_nodes(50);
+        let m = mcts_timed(state("5/5/5/5/5 X"), budget, Rules::default(), config, Some(tx), None);
+        assert!(m.is_some());
+        assert!(rx.try_iter().count() > 0);
+    }
+
+    #[test]
+    fn test_root_parallel_node_limit() {
+        // the node limit holds for the root-parallel trees together, each
+        // thread overshooting by one iteration at most
+        let threads = 4;
+        let config = MctsConfig { parallelism: Parallelism::Root { threads }, ..config(10, true) };
+        let mut search = Mcts::new(state(MIDGAME), Rules::default(), config);
+        search.search(20, None, None);
+        let kept = search.node_table.size();
+        let budget = TimeBudget::from_now(Duration::from_secs(60)).with_max_nodes(50);
+        assert!(search.search_timed(budget, None, None).is_some());
+        assert!(search.node_table.size() - kept <= 50 + threads);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let root = state(MIDGAME);
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let modes = [Parallelism::Leaf, Parallelism::Tree { threads: 2, virtual_loss: 1 }, Parallelism::Root { threads: 2 }];
+        for parallelism in modes {
+            // a cancelled search still runs one iteration per thread at most
+            let config = MctsConfig { parallelism, ..config(10, true) };
+            let mut search = Mcts::new(root, Rules::default(), config);
+            let m = search.search_timed(TimeBudget::from_now(Duration::from_secs(60)), None, Some(&cancel));
+            assert!(m.is_some());
+            assert!(search.node(&root).unwrap().visits <= 2 * 10, "{:?}", parallelism);
+        }
+        // cancelled from another thread, the search returns its best move so far
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        let handle = std::thread::spawn(move || mcts(root, u32::MAX, Rules::default(), config(10, true), None, Some(&cancel)));
+        std::thread::sleep(Duration::from_millis(200));
+        canceller.cancel();
+        let m = handle.join().unwrap().unwrap();
+        assert!(root.play(m).is_ok());
+    }
+
+    #[test]
+    fn test_play_updates_key() {
+        let root = state(MIDGAME);
+        let m = "b5<".parse().unwrap();
+        let child = root.play(m).unwrap();
+        assert_eq!(child, state(MIDGAME_WON));
+        assert_eq!(child.key(), state(MIDGAME_WON).key());
+        assert_ne!(child.key(), root.key());
+        assert_eq!(root.play("c3^".parse().unwrap()), Err(GameError::InnerTile));
+    }
+
+    /// Play the game given by move indices from the empty board, each index
+    /// picking one of the available moves.
+    fn play_indices(indices: &[usize]) -> GameState {
+        let mut s = state("5/5/5/5/5 X");
+        for &i in indices {
+            let (moves, n) = find_available_moves(&s.board, s.player);
+            s = s.play(moves[i % n]).unwrap();
+        }
+        s
+    }
+
+    proptest! {
+        #[test]
+        fn test_make_unmake_matches_apply(indices in vec(0usize..MAX_MOVES, 0..60)) {
+            let mut s = play_indices(&indices);
+            let original = s;
+            for m in ALLOWED_MOVES.iter().chain([Move{x: 2, y: 2, shift: Shift::TOP}].iter()) {
+                match s.make_move(*m) {
+                    Ok(undo) => {
+                        prop_assert_eq!(Ok(s), original.play(*m));
+                        prop_assert_eq!(Ok(s.board), m.apply(original.player, &original.board));
+                        s.unmake_move(undo);
+                    },
+                    Err(e) => prop_assert_eq!(Err(e), m.apply(original.player, &original.board)),
+                }
+                prop_assert_eq!(s, original);
+            }
+        }
+
+        #[test]
+        fn test_make_unmake_sequence(indices in vec(0usize..MAX_MOVES, 1..60)) {
+            let mut s = state("5/5/5/5/5 X");
+            let mut states = Vec::new();
+            let mut undos = Vec::new();
+            for &i in &indices {
+                let (moves, n) = find_available_moves(&s.board, s.player);
+                states.push(s);
+                undos.push(s.make_move(moves[i % n]).unwrap());
+            }
+            prop_assert_eq!(s, play_indices(&indices));
+            while let Some(undo) = undos.pop() {
+                s.unmake_move(undo);
+                prop_assert_eq!(s, states.pop().unwrap());
+            }
+        }
+    }
+
     #[test]
     fn test_get_children() {
-        let available_moves = find_available_moves(&B, Player::X);
-        let children = find_child_states(GameState { board: B, player: Player::X }, &available_moves.0[0..available_moves.1]);
+        let root = state(MIDGAME);
+        let available_moves = find_unique_moves(&root.board, root.player);
+        let children = find_child_states(root, &available_moves.0[0..available_moves.1]);
         let n: Vec<u32> = children.0.iter().take(children.1)
-            .map(|&c| c==GameState{board: B_WON, player: Player::O})
+            .map(|&c| c==state(MIDGAME_WON))
             .map(|c| c as u32)
             .collect();
         println!("{:?}", n);
-        assert!(n.iter().sum::<u32>() == 2);
-        assert!(children.1 == 35);
-        println!("Distinct children: {}", (children.0[0..children.1])
-            .into_iter()
-            .map(|&s| s)
-            .collect::<HashSet<GameState>>().iter().count());
+        // two of the 35 available moves lead to the winning state, and
+        // deduplication keeps a single child for them
+        assert!(n.iter().sum::<u32>() == 1);
+        let distinct_children = (children.0[0..children.1])
+            .iter().copied()
+            .collect::<HashSet<GameState>>().len();
+        assert_eq!(children.1, distinct_children);
+        assert!(children.1 < 35);
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,401 @@
+//! Alpha-beta engine: iterative-deepening negamax with a transposition table
+//! keyed by the Zobrist key of the states, and a static evaluation of the
+//! positions where the search stops.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::cancel::CancelToken;
+use crate::eval::Evaluator;
+use crate::game::{find_unique_moves, History, Move, Rules, Shift, MAX_SIZE};
+use crate::mcts::GameState;
+use crate::zobrist::BuildZobristHasher;
+
+/// Score of a won position, for the side to move. Wins found `ply` moves
+/// from the root score `WIN_SCORE - ply`, so that the fastest win is
+/// preferred; static evaluations must stay below `WIN_SCORE - MAX_DEPTH`.
+pub const WIN_SCORE: i32 = 1_000_000;
+/// Deepest iteration of the search.
+pub const MAX_DEPTH: u32 = 64;
+
+/// How long the search may run. The search stops after the iteration at
+/// the given depth, or when the time is up, whichever comes first; the
+/// first iteration is always completed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SearchLimits {
+    pub depth: Option<u32>,
+    pub time: Option<Duration>,
+}
+
+impl SearchLimits {
+    pub fn depth(depth: u32) -> Self {
+        SearchLimits { depth: Some(depth), time: None }
+    }
+
+    pub fn time(time: Duration) -> Self {
+        SearchLimits { depth: None, time: Some(time) }
+    }
+}
+
+/// Result of the last iteration completed by `negamax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchResult {
+    /// None if the root has no moves or is already decided.
+    pub best_move: Option<Move>,
+    /// Score of the root for the player to move.
+    pub score: i32,
+    /// Depth of the last completed iteration.
+    pub depth: u32,
+    /// Number of states searched, over all iterations.
+    pub nodes: u64,
+}
+
+/// How the score of a table entry bounds the score of its state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bound {
+    Exact,
+    /// The search failed high: the score is at least this.
+    Lower,
+    /// The search failed low: the score is at most this.
+    Upper,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    depth: u32,
+    score: i32,
+    bound: Bound,
+    best_move: Option<Move>,
+}
+
+struct Searcher<const N: usize, E> {
+    rules: Rules,
+    eval: E,
+    table: HashMap<u64, Entry, BuildZobristHasher>,
+    /// Score of the moves that caused cutoffs, by cell and direction.
+    history: [[[u32; 4]; MAX_SIZE]; MAX_SIZE],
+    /// History of the game before the root.
+    game_history: History,
+    /// Keys of the states from the root to the current one.
+    path: Vec<u64>,
+    /// Time after which the search stops, once an iteration is completed.
+    deadline: Option<Instant>,
+    /// Token stopping the search, once an iteration is completed.
+    cancel: Option<CancelToken>,
+    stopped: bool,
+    nodes: u64,
+}
+
+fn shift_index(shift: Shift) -> usize {
+    match shift {
+        Shift::TOP => 0,
+        Shift::BOTTOM => 1,
+        Shift::LEFT => 2,
+        Shift::RIGHT => 3,
+    }
+}
+
+/// Scores of wins are stored in the table relative to the state, and used
+/// relative to the root.
+fn to_table(score: i32, ply: u32) -> i32 {
+    if score > WIN_SCORE - MAX_DEPTH as i32 {
+        score + ply as i32
+    } else if score < -WIN_SCORE + MAX_DEPTH as i32 {
+        score - ply as i32
+    } else {
+        score
+    }
+}
+
+fn from_table(score: i32, ply: u32) -> i32 {
+    if score > WIN_SCORE - MAX_DEPTH as i32 {
+        score - ply as i32
+    } else if score < -WIN_SCORE + MAX_DEPTH as i32 {
+        score + ply as i32
+    } else {
+        score
+    }
+}
+
+impl<const N: usize, E: Evaluator<N>> Searcher<N, E> {
+    fn history(&mut self, m: Move) -> &mut u32 {
+        &mut self.history[m.y as usize][m.x as usize][shift_index(m.shift)]
+    }
+
+    /// Moves of the state, the move of the table entry first, then by
+    /// decreasing history score.
+    fn ordered_moves(&self, state: &GameState<N>, hint: Option<Move>) -> Vec<Move> {
+        let (moves, n) = find_unique_moves(state.board(), state.player());
+        let mut moves = moves[..n].to_vec();
+        let h = &self.history;
+        moves.sort_by_key(|&m| {
+            (Some(m) != hint, std::cmp::Reverse(h[m.y as usize][m.x as usize][shift_index(m.shift)]))
+        });
+        moves
+    }
+
+    fn out_of_time(&mut self) -> bool {
+        if !self.stopped && self.nodes.is_multiple_of(1024) && (self.deadline.is_some_and(|d| Instant::now() >= d) || self.cancelled()) {
+            self.stopped = true;
+        }
+        self.stopped
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    /// Score of the state for the player to move, searched `depth` plies
+    /// deep, `ply` plies below the root.
+    fn search(&mut self, state: &mut GameState<N>, depth: u32, ply: u32, mut alpha: i32, beta: i32) -> (i32, Option<Move>) {
+        self.nodes += 1;
+        if let Some(p) = state.winner(self.rules) {
+            let win = WIN_SCORE - ply as i32;
+            return (if p == state.player() { win } else { -win }, None);
+        }
+        if ply > 0 {
+            let repetitions = 1 + self.game_history.occurrences(state.key())
+                + self.path.iter().filter(|&&k| k == state.key()).count() as u32;
+            if self.rules.draw.is_draw(self.game_history.plies() + ply, repetitions) {
+                return (0, None);
+            }
+        }
+        if depth == 0 {
+            return (self.eval.evaluate(state), None);
+        }
+        let entry = self.table.get(&state.key()).copied();
+        if let Some(e) = entry && ply > 0 && e.depth >= depth {
+            let score = from_table(e.score, ply);
+            let cutoff = match e.bound {
+                Bound::Exact => true,
+                Bound::Lower => score >= beta,
+                Bound::Upper => score <= alpha,
+            };
+            if cutoff {
+                return (score, e.best_move);
+            }
+        }
+        let moves = self.ordered_moves(state, entry.and_then(|e| e.best_move));
+        if moves.is_empty() {
+            return (0, None);
+        }
+        let alpha_start = alpha;
+        let mut best = (-WIN_SCORE - 1, None);
+        self.path.push(state.key());
+        for m in moves {
+            let undo = state.make_move(m).expect("unique moves are legal");
+            let score = -self.search(state, depth - 1, ply + 1, -beta, -alpha).0;
+            state.unmake_move(undo);
+            if self.out_of_time() {
+                self.path.pop();
+                return (0, None);
+            }
+            if score > best.0 {
+                best = (score, Some(m));
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                *self.history(m) += depth * depth;
+                break;
+            }
+        }
+        self.path.pop();
+        let bound = if best.0 <= alpha_start {
+            Bound::Upper
+        } else if best.0 >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        let stored = Entry { depth, score: to_table(best.0, ply), bound, best_move: best.1 };
+        self.table.insert(state.key(), stored);
+        best
+    }
+}
+
+/// Search the best move of the player to move in the root state, with
+/// alpha-beta negamax deepened one ply at a time until the limits are
+/// reached or a forced result is found.
+/// `eval` scores the states where the search stops, for the player to move.
+/// States are drawn by the draw rules, with plies and repetitions counted
+/// from the start of the game whose history led to the root.
+/// Cancelling the token stops the search like the time limit does.
+pub fn negamax<const N: usize>(
+    root: &GameState<N>,
+    history: &History,
+    limits: SearchLimits,
+    rules: Rules,
+    eval: impl Evaluator<N>,
+    cancel: Option<&CancelToken>,
+) -> SearchResult {
+    let mut searcher = Searcher {
+        rules,
+        eval,
+        table: HashMap::default(),
+        history: [[[0; 4]; MAX_SIZE]; MAX_SIZE],
+        game_history: history.clone(),
+        path: Vec::new(),
+        deadline: None,
+        cancel: None,
+        stopped: false,
+        nodes: 0,
+    };
+    let max_depth = limits.depth.unwrap_or(MAX_DEPTH).clamp(1, MAX_DEPTH);
+    let mut result = SearchResult { best_move: None, score: 0, depth: 0, nodes: 0 };
+    let mut state = *root;
+    let start = Instant::now();
+    for depth in 1..=max_depth {
+        let (score, best_move) = searcher.search(&mut state, depth, 0, -WIN_SCORE - 1, WIN_SCORE + 1);
+        if searcher.stopped {
+            break;
+        }
+        result = SearchResult { best_move, score, depth, nodes: searcher.nodes };
+        if best_move.is_none() || score.abs() > WIN_SCORE - MAX_DEPTH as i32 {
+            break;
+        }
+        if depth == 1 {
+            // the first iteration is completed whatever the time limit
+            searcher.deadline = limits.time.map(|t| start + t);
+            searcher.cancel = cancel.cloned();
+        }
+        if searcher.cancelled() {
+            break;
+        }
+    }
+    result.nodes = searcher.nodes;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::LineEvaluator;
+    use crate::game::{DrawRules, Game, Player};
+    use crate::testing::MIDGAME;
+
+    fn state<const N: usize>(s: &str) -> GameState<N> {
+        s.parse().unwrap()
+    }
+
+    /// Tiles of the player to move minus those of the opponent.
+    fn tiles<const N: usize>(s: &GameState<N>) -> i32 {
+        s.board().iter().flatten().flatten().map(|&p| if p == s.player() { 1 } else { -1 }).sum()
+    }
+
+    /// Negamax without pruning nor table.
+    fn plain_negamax<const N: usize>(s: &GameState<N>, depth: u32, ply: u32, rules: Rules) -> i32 {
+        if let Some(p) = s.winner(rules) {
+            let win = WIN_SCORE - ply as i32;
+            return if p == s.player() { win } else { -win };
+        }
+        if depth == 0 {
+            return tiles(s);
+        }
+        let (moves, n) = find_unique_moves(s.board(), s.player());
+        moves[..n].iter()
+            .map(|&m| -plain_negamax(&s.play(m).unwrap(), depth - 1, ply + 1, rules))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_negamax_immediate_win() {
+        let root = state::<5>(MIDGAME);
+        let result = negamax(&root, &History::new(), SearchLimits::depth(3), Rules::default(), tiles, None);
+        assert_eq!(result.score, WIN_SCORE - 1);
+        assert_eq!(result.depth, 1);
+        let next = root.play(result.best_move.unwrap()).unwrap();
+        assert_eq!(next.winner(Rules::default()), Some(Player::X));
+    }
+
+    #[test]
+    fn test_negamax_defends() {
+        // O wins next move unless X breaks the column of O
+        let root = state::<5>("O1XX1/O4/O1X2/O4/1X3 X");
+        let eval = LineEvaluator::new(Rules::default());
+        let result = negamax(&root, &History::new(), SearchLimits::depth(2), Rules::default(), &eval, None);
+        assert!(result.score > -WIN_SCORE + MAX_DEPTH as i32);
+        let next = root.play(result.best_move.unwrap()).unwrap();
+        let reply = negamax(&next, &History::new(), SearchLimits::depth(1), Rules::default(), &eval, None);
+        assert!(reply.score < WIN_SCORE - MAX_DEPTH as i32);
+    }
+
+    #[test]
+    fn test_negamax_matches_plain_negamax() {
+        let rules = Rules { draw: DrawRules::NONE, ..Rules::default() };
+        for depth in 1..=3 {
+            let root = state::<3>("3/3/3 X");
+            assert_eq!(negamax(&root, &History::new(), SearchLimits::depth(depth), rules, tiles, None).score, plain_negamax(&root, depth, 0, rules));
+            let root = state::<4>("4/4/4/4 X");
+            assert_eq!(negamax(&root, &History::new(), SearchLimits::depth(depth), rules, tiles, None).score, plain_negamax(&root, depth, 0, rules));
+        }
+    }
+
+    #[test]
+    fn test_negamax_draw_rules() {
+        let root = state::<5>("5/5/5/5/5 X");
+        let rules = Rules { draw: DrawRules { repetitions: Some(3), max_plies: Some(200) }, ..Rules::default() };
+        assert_eq!(negamax(&root, &History::new(), SearchLimits::depth(1), rules, tiles, None).score, 1);
+        // every move reaches the move limit of the game
+        let mut history = History::new();
+        for _ in 0..199 {
+            history.push(0);
+        }
+        assert_eq!(negamax(&root, &history, SearchLimits::depth(1), rules, tiles, None).score, 0);
+        // X and O shuffle their tiles back and forth: every state not drawn
+        // scores the same
+        let constant = |_: &GameState<5>| 100;
+        let mut game: Game = Game::with_rules([[None; 5]; 5], Player::X, rules);
+        let cycle = ["a1>", "a5>", "e1<", "e5<"];
+        for m in cycle.iter().cycle().take(7) {
+            game.play(m.parse().unwrap()).unwrap();
+        }
+        // the replies reach positions seen once before, not drawn yet
+        let result = negamax(&GameState::from(&game), &game.history(), SearchLimits::depth(1), rules, constant, None);
+        assert_eq!(result.score, -100);
+        for m in ["e5<", "a1>"] {
+            game.play(m.parse().unwrap()).unwrap();
+        }
+        // O can repeat a position for the third time, unless the history
+        // before the root is ignored
+        let root = GameState::from(&game);
+        let result = negamax(&root, &game.history(), SearchLimits::depth(1), rules, constant, None);
+        assert_eq!((result.score, result.best_move), (0, Some("a5>".parse().unwrap())));
+        assert_eq!(negamax(&root, &History::new(), SearchLimits::depth(1), rules, constant, None).score, -100);
+    }
+
+    #[test]
+    fn test_negamax_time_limit() {
+        let root = state::<5>("5/5/5/5/5 X");
+        let start = Instant::now();
+        let result = negamax(&root, &History::new(), SearchLimits::time(Duration::from_millis(100)), Rules::default(), tiles, None);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(result.best_move.is_some());
+        assert!(result.depth >= 1 && result.depth < MAX_DEPTH);
+    }
+
+    #[test]
+    fn test_negamax_cancel() {
+        let root = state::<5>("5/5/5/5/5 X");
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        // the first iteration is completed anyway
+        let result = negamax(&root, &History::new(), SearchLimits::depth(10), Rules::default(), tiles, Some(&cancel));
+        assert_eq!(result.depth, 1);
+        assert!(result.best_move.is_some());
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        let handle = std::thread::spawn(move || negamax(&root, &History::new(), SearchLimits::default(), Rules::default(), tiles, Some(&cancel)));
+        std::thread::sleep(Duration::from_millis(100));
+        canceller.cancel();
+        let result = handle.join().unwrap();
+        assert!(result.best_move.is_some() && result.depth < MAX_DEPTH);
+    }
+
+    #[test]
+    fn test_table_scores() {
+        for score in [0, 1234, -1234, WIN_SCORE - 3, -WIN_SCORE + 5] {
+            assert_eq!(from_table(to_table(score, 4), 4), score);
+        }
+        assert_eq!(from_table(to_table(WIN_SCORE - 3, 2), 0), WIN_SCORE - 1);
+    }
+}
@@ -0,0 +1,118 @@
+//! Perft, the move-generation validator borrowed from chess engines: count
+//! the positions reached after every sequence of moves of a given length.
+//!
+//! Wins are not checked, the game goes on after a line is made, so that the
+//! counts only depend on move generation and on `Move::apply`. The
+//! reference counts in the tests must not change when either is rewritten.
+
+use std::collections::HashSet;
+use crate::game::{find_available_moves, Move};
+use crate::mcts::GameState;
+use crate::zobrist::BuildZobristHasher;
+
+/// Result of `GameState::perft`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Perft {
+    /// Number of sequences of moves of the given length, i.e. leaves of the
+    /// game tree.
+    pub leaves: u64,
+    /// Number of distinct states among the leaves.
+    pub unique: usize,
+}
+
+impl<const N: usize> GameState<N> {
+    /// Count the positions reached after `depth` moves from the state.
+    pub fn perft(&self, depth: u32) -> Perft {
+        let mut state = *self;
+        let mut leaves: HashSet<GameState<N>, BuildZobristHasher> = HashSet::default();
+        let count = perft_rec(&mut state, depth, &mut |s| {
+            leaves.insert(*s);
+        });
+        Perft { leaves: count, unique: leaves.len() }
+    }
+
+    /// Number of leaves after `depth` moves below each move of the state,
+    /// in the order of `find_available_moves`, to find which move a wrong
+    /// count comes from.
+    pub fn perft_divide(&self, depth: u32) -> Vec<(Move, u64)> {
+        let mut state = *self;
+        let (moves, n) = find_available_moves(self.board(), self.player());
+        moves[..n].iter().map(|&m| {
+            let undo = state.make_move(m).expect("available moves are legal");
+            let count = perft_rec(&mut state, depth.saturating_sub(1), &mut |_| {});
+            state.unmake_move(undo);
+            (m, count)
+        }).collect()
+    }
+}
+
+/// Count the leaves below the state, calling `leaf` on each of them.
+fn perft_rec<const N: usize>(state: &mut GameState<N>, depth: u32, leaf: &mut impl FnMut(&GameState<N>)) -> u64 {
+    if depth == 0 {
+        leaf(state);
+        return 1;
+    }
+    let (moves, n) = find_available_moves(state.board(), state.player());
+    let mut count = 0;
+    for &m in &moves[..n] {
+        let undo = state.make_move(m).expect("available moves are legal");
+        count += perft_rec(state, depth - 1, leaf);
+        state.unmake_move(undo);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitboard::{Bitboard, SideBitboard};
+    use crate::game::Player;
+
+    /// Leaf count computed with `Bitboard` instead of `Board`.
+    fn bitboard_perft<const N: usize>(b: Bitboard<N>, p: Player, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let (moves, n) = b.available_moves(p);
+        moves[..n].iter().map(|&m| bitboard_perft(b.apply_unchecked(m, p), p.next(), depth - 1)).sum()
+    }
+
+    /// Check the reference counts of the state for depths 0, 1, ...
+    fn check_perft<const N: usize>(position: &str, expected: &[(u64, usize)]) {
+        let state: GameState<N> = position.parse().unwrap();
+        for (depth, &(leaves, unique)) in expected.iter().enumerate() {
+            let depth = depth as u32;
+            assert_eq!(state.perft(depth), Perft { leaves, unique }, "{} at depth {}", position, depth);
+            let bb = Bitboard::from(state.board());
+            assert_eq!(bitboard_perft(bb, state.player(), depth), leaves, "{} at depth {}", position, depth);
+        }
+    }
+
+    #[test]
+    fn test_perft_empty_board() {
+        check_perft::<5>("5/5/5/5/5 X", &[(1, 1), (44, 16), (1836, 240), (76596, 2240)]);
+    }
+
+    #[test]
+    fn test_perft_midgame() {
+        check_perft::<5>("XXO2/O3X/X3O/1O3/OX2X O", &[(1, 1), (28, 25), (887, 665), (23985, 11396)]);
+    }
+
+    #[test]
+    fn test_perft_other_sizes() {
+        check_perft::<3>("3/3/3 X", &[(1, 1), (20, 8), (356, 56), (6332, 274)]);
+        check_perft::<4>("4/4/4/4 O", &[(1, 1), (32, 12), (952, 132), (28312, 956)]);
+    }
+
+    #[test]
+    fn test_perft_divide() {
+        let state: GameState = "XXO2/O3X/X3O/1O3/OX2X O".parse().unwrap();
+        let divide = state.perft_divide(3);
+        assert_eq!(divide.len(), 28);
+        assert_eq!(divide.iter().map(|&(_, n)| n).sum::<u64>(), 23985);
+        for (m, n) in divide {
+            assert_eq!(state.play(m).unwrap().perft(2).leaves, n);
+        }
+    }
+}
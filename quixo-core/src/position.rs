@@ -0,0 +1,210 @@
+//! Compact position strings, in the spirit of chess FEN.
+//!
+//! A position is written as the rows of the board from top to bottom,
+//! separated by `/`, followed by the player to move and optionally by the
+//! number of plies played so far, e.g. `XXX2/OX3/1X3/OX3/X1O2 O 12`.
+//! In a row, `X` and `O` are tiles of the players and a digit from 1 to the
+//! size of the board stands for that many consecutive neutral tiles.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use crate::game::{Board, Player};
+use crate::mcts::GameState;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Error returned when parsing a malformed position string.
+pub enum ParsePositionError {
+    /// The board does not have as many rows as the size of the board.
+    RowCount(usize),
+    /// The row (counted from 0) does not describe as many cells as the size
+    /// of the board.
+    RowLength(usize),
+    /// The row (counted from 0) contains an invalid character.
+    InvalidCell(usize, char),
+    MissingPlayer,
+    InvalidPlayer(String),
+    InvalidPly(String),
+    /// Unexpected input after the ply counter.
+    TrailingInput(String),
+}
+
+impl Display for ParsePositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RowCount(n) => write!(f, "wrong number of rows separated by '/': {}", n),
+            Self::RowLength(r) => write!(f, "row {} does not have one cell per row of the board", r + 1),
+            Self::InvalidCell(r, c) => write!(f, "invalid character '{}' in row {}, expected X, O or a digit", c, r + 1),
+            Self::MissingPlayer => write!(f, "missing player to move"),
+            Self::InvalidPlayer(s) => write!(f, "invalid player to move '{}'", s),
+            Self::InvalidPly(s) => write!(f, "invalid ply counter '{}'", s),
+            Self::TrailingInput(s) => write!(f, "unexpected input '{}' after the position", s),
+        }
+    }
+}
+impl std::error::Error for ParsePositionError {}
+
+type Result<T> = std::result::Result<T, ParsePositionError>;
+
+/// Format the board part of a position string.
+pub fn format_board<const N: usize>(b: &Board<N>) -> String {
+    format_cells(b, |p| match p {
+        Player::X => 'X',
+        Player::O => 'O',
+    })
+}
+
+/// Format the rows of a board whose tiles are written as one character by
+/// `tile`, neutral cells being counted with digits.
+pub(crate) fn format_cells<T: Copy, const N: usize>(b: &[[Option<T>; N]; N], tile: impl Fn(T) -> char) -> String {
+    let mut s = String::new();
+    for (i, row) in b.iter().enumerate() {
+        if i > 0 {
+            s.push('/');
+        }
+        let mut empty = 0;
+        for cell in row {
+            match cell {
+                Some(t) => {
+                    if empty > 0 {
+                        s.push_str(&empty.to_string());
+                        empty = 0;
+                    }
+                    s.push(tile(*t));
+                },
+                None => empty += 1,
+            }
+        }
+        if empty > 0 {
+            s.push_str(&empty.to_string());
+        }
+    }
+    s
+}
+
+/// Parse the board part of a position string.
+pub fn parse_board<const N: usize>(s: &str) -> Result<Board<N>> {
+    parse_cells(s, |c| match c {
+        'X' => Some(Player::X),
+        'O' => Some(Player::O),
+        _ => None,
+    })
+}
+
+/// Parse the rows of a board, `tile` giving the tile written with a
+/// character, if any.
+pub(crate) fn parse_cells<T: Copy, const N: usize>(s: &str, tile: impl Fn(char) -> Option<T>) -> Result<[[Option<T>; N]; N]> {
+    let rows: Vec<&str> = s.split('/').collect();
+    if rows.len() != N {
+        return Err(ParsePositionError::RowCount(rows.len()));
+    }
+    let mut b = [[None; N]; N];
+    for (y, row) in rows.iter().enumerate() {
+        let mut x = 0;
+        for c in row.chars() {
+            let (cell, n) = match (tile(c), c) {
+                (Some(t), _) => (Some(t), 1),
+                (None, '1'..='8') => (None, c as usize - '0' as usize),
+                _ => return Err(ParsePositionError::InvalidCell(y, c)),
+            };
+            if x + n > N {
+                return Err(ParsePositionError::RowLength(y));
+            }
+            b[y][x..x + n].fill(cell);
+            x += n;
+        }
+        if x != N {
+            return Err(ParsePositionError::RowLength(y));
+        }
+    }
+    Ok(b)
+}
+
+impl<const N: usize> GameState<N> {
+    /// Parse a position string, returning the state and the ply counter if
+    /// the string has one.
+    pub fn parse_with_ply(s: &str) -> Result<(GameState<N>, Option<u32>)> {
+        let mut fields = s.split_whitespace();
+        let board = parse_board(fields.next().unwrap_or(""))?;
+        let player = match fields.next() {
+            Some("X") => Player::X,
+            Some("O") => Player::O,
+            Some(p) => return Err(ParsePositionError::InvalidPlayer(p.to_string())),
+            None => return Err(ParsePositionError::MissingPlayer),
+        };
+        let ply = match fields.next() {
+            Some(n) => Some(n.parse().map_err(|_| ParsePositionError::InvalidPly(n.to_string()))?),
+            None => None,
+        };
+        if let Some(rest) = fields.next() {
+            return Err(ParsePositionError::TrailingInput(rest.to_string()));
+        }
+        Ok((GameState::new(board, player), ply))
+    }
+
+    /// Format the state as a position string with the given ply counter.
+    pub fn to_string_with_ply(&self, ply: u32) -> String {
+        format!("{} {}", self, ply)
+    }
+}
+
+/// Formats the position string of the state, without ply counter.
+impl<const N: usize> Display for GameState<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", format_board(self.board()), self.player())
+    }
+}
+
+/// Parses a position string, ignoring the ply counter if present.
+impl<const N: usize> FromStr for GameState<N> {
+    type Err = ParsePositionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        GameState::parse_with_ply(s).map(|(state, _)| state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let s = "XXX2/OX3/1X3/OX3/X1O2 X";
+        let state: GameState = s.parse().unwrap();
+        assert_eq!(state.board()[0], [Some(Player::X), Some(Player::X), Some(Player::X), None, None]);
+        assert_eq!(state.board()[4], [Some(Player::X), None, Some(Player::O), None, None]);
+        assert_eq!(state.player(), Player::X);
+        assert_eq!(state.to_string(), s);
+        assert_eq!(GameState::parse_with_ply("5/5/5/5/5 O 7"), Ok((GameState::new([[None; 5]; 5], Player::O), Some(7))));
+        assert_eq!(state.to_string_with_ply(12), "XXX2/OX3/1X3/OX3/X1O2 X 12");
+        assert_eq!(format_board(&[[Some(Player::O); 5]; 5]), "OOOOO/OOOOO/OOOOO/OOOOO/OOOOO");
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |s: &str| GameState::<5>::parse_with_ply(s).unwrap_err();
+        assert_eq!(err(""), ParsePositionError::RowCount(1));
+        assert_eq!(err("5/5/5/5 X"), ParsePositionError::RowCount(4));
+        assert_eq!(err("5/5/6/5/5 X"), ParsePositionError::RowLength(2));
+        assert_eq!(err("5/5/5/XX4/5 X"), ParsePositionError::RowLength(3));
+        assert_eq!(err("5/5/5/XX2/5 X"), ParsePositionError::RowLength(3));
+        assert_eq!(err("5/5/5/5/x4 X"), ParsePositionError::InvalidCell(4, 'x'));
+        assert_eq!(err("5/5/5/5/5"), ParsePositionError::MissingPlayer);
+        assert_eq!(err("5/5/5/5/5 Y"), ParsePositionError::InvalidPlayer(String::from("Y")));
+        assert_eq!(err("5/5/5/5/5 X -1"), ParsePositionError::InvalidPly(String::from("-1")));
+        assert_eq!(err("5/5/5/5/5 X 1 2"), ParsePositionError::TrailingInput(String::from("2")));
+    }
+
+    #[test]
+    fn test_other_sizes() {
+        let state: GameState<3> = "X1O/3/2X O".parse().unwrap();
+        assert_eq!(state.board()[0], [Some(Player::X), None, Some(Player::O)]);
+        assert_eq!(state.to_string(), "X1O/3/2X O");
+        let state: GameState<8> = "8/8/8/8/8/8/8/X6O X".parse().unwrap();
+        assert_eq!(state.board()[7][7], Some(Player::O));
+        assert_eq!(state.to_string(), "8/8/8/8/8/8/8/X6O X");
+        assert_eq!(parse_board::<4>("5/5/5/5/5"), Err(ParsePositionError::RowCount(5)));
+        assert_eq!(parse_board::<4>("4/4/4/5"), Err(ParsePositionError::RowLength(3)));
+        assert_eq!(parse_board::<5>("5/5/5/5/9"), Err(ParsePositionError::InvalidCell(4, '9')));
+    }
+}
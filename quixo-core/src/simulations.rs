@@ -1,7 +1,9 @@
 use rayon::prelude::*;
-use crate::game::{Board, Player, random_game};
+use crate::bitboard::{Bitboard, random_game};
+use crate::game::{Board, Player, Rules};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Result {
     pub wins_x: u32,
     pub wins_o: u32,
@@ -9,10 +11,16 @@ pub struct Result {
     pub total: u32,
 }
 
-pub fn parallel_simulation(b: Board, p: Player, n: u32) -> Result {
+pub fn parallel_simulation<const N: usize>(b: Board<N>, p: Player, n: u32, rules: Rules) -> Result {
+    let b = Bitboard::from(b);
+    parallel_games(n, || random_game(b, p, rules))
+}
+
+/// Play n games in parallel, `game` playing one and returning its winner.
+pub fn parallel_games(n: u32, game: impl Fn() -> Option<Player> + Sync + Send) -> Result {
     let (wins_x, wins_o, draws) =    (0..n).into_par_iter()
-        .map(|_| random_game(b, p))
-        .fold(|| (0 as u32,0 as u32, 0 as u32), |(wins_x, wins_o, draws), game| {
+        .map(|_| game())
+        .fold(|| (0_u32,0_u32, 0_u32), |(wins_x, wins_o, draws), game| {
             match game {
                 Some(Player::X) => (wins_x + 1, wins_o, draws),
                 Some(Player::O) => (wins_x, wins_o + 1, draws),
@@ -35,14 +43,36 @@ pub fn parallel_simulation(b: Board, p: Player, n: u32) -> Result {
 mod tests {
 
     use super::*;
-    use crate::game::{Board, Player};
+    use crate::game::{Board, DrawRules, Player};
+    use crate::position::parse_board;
+
+    #[test]
+    fn test_parallel_simulation_counts_draws() {
+        let b: Board = [[None;5];5];
+        let rules = Rules { draw: DrawRules { repetitions: None, max_plies: Some(4) }, ..Rules::default() };
+        let result = parallel_simulation(b, Player::X, 1000, rules);
+        // no line can be completed in 4 plies
+        assert_eq!(result.draws, 1000);
+    }
+
+    #[test]
+    fn test_parallel_simulation_other_sizes() {
+        // with three in a row on a 3x3 board, games are short and rarely drawn
+        let b: Board<3> = [[None; 3]; 3];
+        let result = parallel_simulation(b, Player::X, 1000, Rules::default());
+        assert_eq!(result.wins_x + result.wins_o + result.draws, 1000);
+        assert!(result.draws < 100);
+        let b: Board<6> = [[None; 6]; 6];
+        let result = parallel_simulation(b, Player::X, 100, Rules::default());
+        assert_eq!(result.wins_x + result.wins_o + result.draws, 100);
+    }
 
     #[test]
     fn test_parallel_simulation_from_empty_board() {
         let b: Board = [[None;5];5];
         let p = Player::X;
         let n = 100000;
-        let result = parallel_simulation(b, p, n);
+        let result = parallel_simulation(b, p, n, Rules::default());
         
         assert_eq!(result.total, n);
         assert_eq!(result.wins_x + result.wins_o + result.draws, n);
@@ -51,16 +81,10 @@ mod tests {
 
     #[test]
     fn test_parallel_simulation_when_x_almost_won() {
-        let b: Board = [
-            [Some(Player::X), Some(Player::X), Some(Player::X), Some(Player::X), Some(Player::O)],
-            [Some(Player::O), Some(Player::X), None, None, None],
-            [None, Some(Player::X), None, None, None],
-            [Some(Player::O), Some(Player::X), None, None, None],
-            [None, None, Some(Player::O), None, None],
-        ];
+        let b: Board = parse_board("XXXXO/OX3/1X3/OX3/2O2").unwrap();
         let p = Player::X;
         let n = 100000;
-        let result = parallel_simulation(b, p, n);
+        let result = parallel_simulation(b, p, n, Rules::default());
         
         assert_eq!(result.total, n);
         assert_eq!(result.wins_x + result.wins_o + result.draws, n);
@@ -0,0 +1,235 @@
+use crate::bitboard::{Bitboard, cell_mask};
+use crate::game::{Board, Move, Shift};
+use crate::mcts::GameState;
+
+/// One of the 8 symmetries of the square board.
+/// Rotations are clockwise as the board is displayed, with row 0 on top.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Symmetry {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// Swap the left and right columns.
+    MirrorHorizontal,
+    /// Swap the top and bottom rows.
+    MirrorVertical,
+    /// Reflect across the diagonal from the top left corner.
+    Transpose,
+    /// Reflect across the diagonal from the top right corner.
+    AntiTranspose,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity,
+        Symmetry::Rotate90,
+        Symmetry::Rotate180,
+        Symmetry::Rotate270,
+        Symmetry::MirrorHorizontal,
+        Symmetry::MirrorVertical,
+        Symmetry::Transpose,
+        Symmetry::AntiTranspose,
+    ];
+
+    /// The symmetry undoing this one.
+    pub fn inverse(self) -> Symmetry {
+        match self {
+            Symmetry::Rotate90 => Symmetry::Rotate270,
+            Symmetry::Rotate270 => Symmetry::Rotate90,
+            s => s,
+        }
+    }
+
+    /// Image of the vector (dx, dy), relative to the center of the board.
+    /// Vectors are doubled so that the center of even sized boards, between
+    /// cells, has integer coordinates.
+    fn apply_vector(self, dx: i8, dy: i8) -> (i8, i8) {
+        match self {
+            Symmetry::Identity => (dx, dy),
+            Symmetry::Rotate90 => (-dy, dx),
+            Symmetry::Rotate180 => (-dx, -dy),
+            Symmetry::Rotate270 => (dy, -dx),
+            Symmetry::MirrorHorizontal => (-dx, dy),
+            Symmetry::MirrorVertical => (dx, -dy),
+            Symmetry::Transpose => (dy, dx),
+            Symmetry::AntiTranspose => (-dy, -dx),
+        }
+    }
+
+    /// Image of the cell in column x and row y of a board of size N.
+    pub fn apply_point<const N: usize>(self, x: u8, y: u8) -> (u8, u8) {
+        let last = N as i8 - 1;
+        let (dx, dy) = self.apply_vector(2 * x as i8 - last, 2 * y as i8 - last);
+        (((dx + last) / 2) as u8, ((dy + last) / 2) as u8)
+    }
+
+    pub fn apply_shift(self, shift: Shift) -> Shift {
+        let v = match shift {
+            Shift::TOP => (0, -1),
+            Shift::BOTTOM => (0, 1),
+            Shift::LEFT => (-1, 0),
+            Shift::RIGHT => (1, 0),
+        };
+        match self.apply_vector(v.0, v.1) {
+            (0, -1) => Shift::TOP,
+            (0, 1) => Shift::BOTTOM,
+            (-1, 0) => Shift::LEFT,
+            _ => Shift::RIGHT,
+        }
+    }
+
+    /// The move playing on the transformed board of size N what m plays on
+    /// the original one.
+    pub fn apply_move<const N: usize>(self, m: Move) -> Move {
+        let (x, y) = self.apply_point::<N>(m.x, m.y);
+        Move { x, y, shift: self.apply_shift(m.shift) }
+    }
+
+    /// Image of a board, whatever its cells hold.
+    pub fn apply_board<T: Copy, const N: usize>(self, b: &[[T; N]; N]) -> [[T; N]; N] {
+        let mut new_board = *b;
+        for (y, row) in b.iter().enumerate() {
+            for (x, &cell) in row.iter().enumerate() {
+                let (nx, ny) = self.apply_point::<N>(x as u8, y as u8);
+                new_board[ny as usize][nx as usize] = cell;
+            }
+        }
+        new_board
+    }
+
+    pub fn apply_bitboard<const N: usize>(self, b: Bitboard<N>) -> Bitboard<N> {
+        if self == Symmetry::Identity {
+            return b;
+        }
+        let mut new_board = Bitboard::<N>::empty();
+        for y in 0..N as u8 {
+            for x in 0..N as u8 {
+                let m = cell_mask::<N>(x, y);
+                if (b.x | b.o) & m != 0 {
+                    let (nx, ny) = self.apply_point::<N>(x, y);
+                    let n = cell_mask::<N>(nx, ny);
+                    if b.x & m != 0 {
+                        new_board.x |= n;
+                    } else {
+                        new_board.o |= n;
+                    }
+                }
+            }
+        }
+        new_board
+    }
+}
+
+impl<const N: usize> Bitboard<N> {
+    pub fn transform(self, s: Symmetry) -> Bitboard<N> {
+        s.apply_bitboard(self)
+    }
+
+    /// The board rotated by 90 degrees clockwise.
+    pub fn rotate(self) -> Bitboard<N> {
+        self.transform(Symmetry::Rotate90)
+    }
+
+    /// The board with left and right swapped.
+    pub fn mirror(self) -> Bitboard<N> {
+        self.transform(Symmetry::MirrorHorizontal)
+    }
+
+    /// The smallest of the 8 symmetric boards, and the symmetry mapping this
+    /// board to it.
+    pub fn canonical(self) -> (Bitboard<N>, Symmetry) {
+        Symmetry::ALL.into_iter()
+            .map(|s| (self.transform(s), s))
+            .min_by_key(|(b, _)| (b.x, b.o))
+            .unwrap()
+    }
+}
+
+impl<const N: usize> GameState<N> {
+    pub fn transform(&self, s: Symmetry) -> GameState<N> {
+        GameState::new(s.apply_board(self.board()), self.player())
+    }
+
+    /// The state rotated by 90 degrees clockwise.
+    pub fn rotate(&self) -> GameState<N> {
+        self.transform(Symmetry::Rotate90)
+    }
+
+    /// The state with left and right swapped.
+    pub fn mirror(&self) -> GameState<N> {
+        self.transform(Symmetry::MirrorHorizontal)
+    }
+
+    /// A representative shared by the 8 symmetric states, and the symmetry
+    /// mapping this state to it. Moves of this state are mapped to moves of
+    /// the canonical state with `Symmetry::apply_move`, and back with the
+    /// inverse symmetry.
+    pub fn canonical(&self) -> (GameState<N>, Symmetry) {
+        let (b, s) = Bitboard::from(self.board()).canonical();
+        (GameState::new(Board::from(b), self.player()), s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{ALLOWED_MOVES, Player, random_move};
+
+    #[test]
+    fn test_rotate_and_mirror() {
+        let state: GameState = "XX3/O4/5/5/4O X".parse().unwrap();
+        assert_eq!(state.rotate().to_string(), "3OX/4X/5/5/O4 X");
+        assert_eq!(state.mirror().to_string(), "3XX/4O/5/5/O4 X");
+        assert_eq!(state.rotate().rotate().rotate().rotate(), state);
+        for s in Symmetry::ALL {
+            assert_eq!(state.transform(s).transform(s.inverse()), state);
+        }
+    }
+
+    #[test]
+    fn test_moves_commute_with_symmetries() {
+        let mut b: Board = [[None; 5]; 5];
+        let mut p = Player::X;
+        for _ in 0..30 {
+            for s in Symmetry::ALL {
+                for m in ALLOWED_MOVES {
+                    let moved = m.apply(p, &b).map(|b| s.apply_board(&b));
+                    assert_eq!(moved, s.apply_move::<5>(m).apply(p, &s.apply_board(&b)));
+                }
+                assert_eq!(Bitboard::from(s.apply_board(&b)), Bitboard::from(b).transform(s));
+            }
+            b = random_move(&b, p).unwrap().apply(p, &b).unwrap();
+            p = p.next();
+        }
+    }
+
+    #[test]
+    fn test_canonical() {
+        let state: GameState = "XX3/O4/5/5/4O O".parse().unwrap();
+        let (canonical, s) = state.canonical();
+        assert_eq!(state.transform(s), canonical);
+        for t in Symmetry::ALL {
+            assert_eq!(state.transform(t).canonical().0, canonical);
+            assert_eq!(state.transform(t).symmetric_key(), state.symmetric_key());
+        }
+        let m = Move { x: 4, y: 4, shift: Shift::LEFT };
+        assert_eq!(
+            state.play(m).map(|next| next.transform(s)),
+            canonical.play(s.apply_move::<5>(m)),
+        );
+    }
+
+    #[test]
+    fn test_even_sized_boards() {
+        let state: GameState<4> = "XX2/O3/4/3O X".parse().unwrap();
+        assert_eq!(state.rotate().to_string(), "2OX/3X/4/O3 X");
+        assert_eq!(state.mirror().to_string(), "2XX/3O/4/O3 X");
+        for s in Symmetry::ALL {
+            assert_eq!(state.transform(s).transform(s.inverse()), state);
+            assert_eq!(Bitboard::from(state.transform(s).board()), Bitboard::from(state.board()).transform(s));
+        }
+        assert_eq!(Symmetry::Rotate90.apply_move::<4>("a1>".parse().unwrap()), "d1v".parse().unwrap());
+    }
+}
@@ -0,0 +1,410 @@
+//! Four-player team Quixo.
+//!
+//! Four seats play in turn X1, O1, X2, O2: X1 and X2 form team X, O1 and O2
+//! team O. Each seat owns its tiles, and may only pick up neutral tiles or
+//! its own tiles, not those of its partner. Lines are made of the tiles of
+//! a team, whichever seat of the team owns them, and are scored like in the
+//! two-player game with the team of the seat that moved as the mover.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use crate::bitboard::{random_game, Bitboard, SideBitboard};
+use crate::game::{Board, GameError, GameRecord, Move, Player, Rules, Shift, Side, MAX_MOVES};
+use crate::mcts::SearchState;
+use crate::position::{format_cells, parse_cells, ParsePositionError};
+use crate::simulations::{parallel_games, Result};
+use crate::symmetry::Symmetry;
+use crate::zobrist;
+
+/// A seat of the four-player game, in turn order.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Seat {
+    X1,
+    O1,
+    X2,
+    O2,
+}
+
+impl Seat {
+    pub const ALL: [Seat; 4] = [Seat::X1, Seat::O1, Seat::X2, Seat::O2];
+
+    /// Position of the seat in turn order.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The seat playing after this one.
+    pub fn next(self) -> Seat {
+        Seat::ALL[(self.index() + 1) % 4]
+    }
+
+    pub fn team(self) -> Player {
+        match self {
+            Seat::X1 | Seat::X2 => Player::X,
+            Seat::O1 | Seat::O2 => Player::O,
+        }
+    }
+
+    /// The other seat of the team.
+    pub fn partner(self) -> Seat {
+        Seat::ALL[(self.index() + 2) % 4]
+    }
+
+    /// Whether the seat is the second one of its team to play.
+    fn is_second(self) -> bool {
+        self.index() >= 2
+    }
+}
+
+impl Side for Seat {
+    fn next(self) -> Seat {
+        Seat::next(self)
+    }
+
+    fn team(self) -> Player {
+        Seat::team(self)
+    }
+}
+
+impl Display for Seat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.team(), self.index() / 2 + 1)
+    }
+}
+
+/// A board of the four-player game, each tile belonging to a seat.
+pub type SeatBoard<const N: usize = 5> = [[Option<Seat>; N]; N];
+
+/// The board of the teams owning the tiles.
+pub fn team_board<const N: usize>(b: &SeatBoard<N>) -> Board<N> {
+    b.map(|row| row.map(|cell| cell.map(Seat::team)))
+}
+
+/// A board of the four-player game packed into bitboards: the tiles of the
+/// teams, and which of them belong to the second seat of their team.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct TeamBitboard<const N: usize = 5> {
+    pub teams: Bitboard<N>,
+    pub second: u64,
+}
+
+impl<const N: usize> SideBitboard<N> for TeamBitboard<N> {
+    type Side = Seat;
+
+    /// Neutral tiles and those of seat s.
+    fn movable(&self, s: Seat) -> u64 {
+        let team = self.teams.mask(s.team());
+        let partner = if s.is_second() { team & !self.second } else { team & self.second };
+        self.teams.movable(s.team()) & !partner
+    }
+
+    fn apply_unchecked(&self, m: Move, s: Seat) -> TeamBitboard<N> {
+        let (second, destination) = Bitboard::<N>::slide(self.second, m);
+        TeamBitboard {
+            teams: self.teams.apply_unchecked(m, s.team()),
+            second: if s.is_second() { second | destination } else { second },
+        }
+    }
+
+    fn teams(&self) -> Bitboard<N> {
+        self.teams
+    }
+}
+
+impl<const N: usize> From<&SeatBoard<N>> for TeamBitboard<N> {
+    fn from(b: &SeatBoard<N>) -> Self {
+        let mut bb = TeamBitboard::<N>::default();
+        for (y, row) in b.iter().enumerate() {
+            for (x, &cell) in row.iter().enumerate() {
+                bb.teams.set(x as u8, y as u8, cell.map(Seat::team));
+                if cell.is_some_and(Seat::is_second) {
+                    bb.second |= crate::bitboard::cell_mask::<N>(x as u8, y as u8);
+                }
+            }
+        }
+        bb
+    }
+}
+
+/// A board of the four-player game with the seat to move, and the Zobrist
+/// keys of the state and of its symmetric states, like `GameState`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TeamState<const N: usize = 5> {
+    board: SeatBoard<N>,
+    seat: Seat,
+    /// Keys of the 8 symmetric states in `Symmetry::ALL` order, that of the
+    /// state first.
+    keys: [u64; 8],
+}
+
+impl<const N: usize> TeamState<N> {
+    pub fn new(board: SeatBoard<N>, seat: Seat) -> Self {
+        TeamState { board, seat, keys: zobrist::symmetric_seat_keys(&board, seat) }
+    }
+
+    pub fn board(&self) -> &SeatBoard<N> {
+        &self.board
+    }
+
+    /// The seat to move.
+    pub fn seat(&self) -> Seat {
+        self.seat
+    }
+
+    pub fn key(&self) -> u64 {
+        self.keys[0]
+    }
+
+    /// A key shared by the 8 symmetric states: the smallest of their keys.
+    pub fn symmetric_key(&self) -> u64 {
+        *self.keys.iter().min().expect("there are 8 keys")
+    }
+
+    /// Check that the seat to move can play m.
+    pub fn validate(&self, m: Move) -> std::result::Result<(), GameError> {
+        m.validate_with::<N>(|x, y| self.board[y as usize][x as usize].is_none_or(|s| s == self.seat))
+    }
+
+    /// The state reached when the seat to move plays m. The keys are
+    /// updated incrementally.
+    pub fn play(&self, m: Move) -> std::result::Result<TeamState<N>, GameError> {
+        self.validate(m)?;
+        let keys = zobrist::update_seat_symmetric(self.keys, &self.board, m, self.seat);
+        let mut board = self.board;
+        m.shift_board(Some(self.seat), &mut board);
+        Ok(TeamState { board, seat: self.seat.next(), keys })
+    }
+
+    pub fn available_moves(&self) -> ([Move; MAX_MOVES], usize) {
+        TeamBitboard::from(&self.board).available_moves(self.seat)
+    }
+
+    /// Winning team of the state under the given rules, the last move having
+    /// been made by the other team.
+    pub fn winner(&self, rules: Rules) -> Option<Player> {
+        Bitboard::from(team_board(&self.board)).winner_after_move(self.seat.team().next(), rules)
+    }
+
+    pub fn transform(&self, s: Symmetry) -> TeamState<N> {
+        TeamState::new(s.apply_board(&self.board), self.seat)
+    }
+}
+
+impl<const N: usize> SearchState for TeamState<N> {
+    fn side(&self) -> Player {
+        self.seat.team()
+    }
+
+    fn key(&self) -> u64 {
+        TeamState::key(self)
+    }
+
+    fn symmetric_key(&self) -> u64 {
+        TeamState::symmetric_key(self)
+    }
+
+    fn winner(&self, rules: Rules) -> Option<Player> {
+        TeamState::winner(self, rules)
+    }
+
+    fn available_moves(&self) -> ([Move; MAX_MOVES], usize) {
+        TeamState::available_moves(self)
+    }
+
+    fn unique_moves(&self) -> ([Move; MAX_MOVES], usize) {
+        let (unique_moves, num_unique_moves) = TeamBitboard::from(&self.board).unique_moves(self.seat);
+        let mut moves = [Move { x: 0, y: 0, shift: Shift::TOP }; MAX_MOVES];
+        for (m, &(u, _)) in moves.iter_mut().zip(&unique_moves[..num_unique_moves]) {
+            *m = u;
+        }
+        (moves, num_unique_moves)
+    }
+
+    fn play(&self, m: Move) -> std::result::Result<Self, GameError> {
+        TeamState::play(self, m)
+    }
+
+    fn rollouts(&self, n: u32, rules: Rules) -> Result {
+        let b = TeamBitboard::from(&self.board);
+        let seat = self.seat;
+        parallel_games(n, || random_game(b, seat, rules))
+    }
+}
+
+/// Formats the position string of the state: the rows of the board with
+/// `X` and `O` for the tiles of X1 and O1, `x` and `o` for those of X2 and
+/// O2, followed by the seat to move, e.g. `Xx3/5/5/5/o3O O2`.
+impl<const N: usize> Display for TeamState<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tile = |s: Seat| match s {
+            Seat::X1 => 'X',
+            Seat::O1 => 'O',
+            Seat::X2 => 'x',
+            Seat::O2 => 'o',
+        };
+        write!(f, "{} {}", format_cells(&self.board, tile), self.seat)
+    }
+}
+
+impl<const N: usize> FromStr for TeamState<N> {
+    type Err = ParsePositionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let board = parse_cells(fields.next().unwrap_or(""), |c| match c {
+            'X' => Some(Seat::X1),
+            'O' => Some(Seat::O1),
+            'x' => Some(Seat::X2),
+            'o' => Some(Seat::O2),
+            _ => None,
+        })?;
+        let seat = match fields.next() {
+            Some(p) => Seat::ALL.into_iter()
+                .find(|s| s.to_string() == p)
+                .ok_or_else(|| ParsePositionError::InvalidPlayer(p.to_string()))?,
+            None => return Err(ParsePositionError::MissingPlayer),
+        };
+        if let Some(rest) = fields.next() {
+            return Err(ParsePositionError::TrailingInput(rest.to_string()));
+        }
+        Ok(TeamState::new(board, seat))
+    }
+}
+
+/// A four-player game in progress, like `Game` for two players.
+pub type TeamGame<const N: usize = 5> = GameRecord<TeamState<N>>;
+
+impl<const N: usize> Default for TeamGame<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TeamGame<N> {
+    /// A new game on the empty board, X1 moves first.
+    pub fn new() -> Self {
+        Self::with_rules(TeamState::new([[None; N]; N], Seat::X1), Rules::default())
+    }
+
+    /// A new game starting from the given state, played according to the
+    /// given rules.
+    pub fn with_rules(state: TeamState<N>, rules: Rules) -> Self {
+        Self::from_state(state, rules)
+    }
+
+    pub fn board(&self) -> &SeatBoard<N> {
+        self.state().board()
+    }
+
+    /// The seat to move.
+    pub fn seat(&self) -> Seat {
+        self.state().seat()
+    }
+}
+
+impl<const N: usize> From<&TeamGame<N>> for TeamState<N> {
+    fn from(game: &TeamGame<N>) -> Self {
+        *game.state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use crate::game::GameStatus;
+    use crate::mcts::{mcts, MctsConfig};
+    use crate::testing::team_state;
+
+    #[test]
+    fn test_turn_order() {
+        assert_eq!(Seat::ALL.map(Seat::next), [Seat::O1, Seat::X2, Seat::O2, Seat::X1]);
+        assert_eq!(Seat::ALL.map(Seat::team), [Player::X, Player::O, Player::X, Player::O]);
+        assert_eq!(Seat::X1.partner(), Seat::X2);
+        assert_eq!(Seat::O2.partner(), Seat::O1);
+        let mut game: TeamGame = TeamGame::new();
+        for (m, seat) in ["a1>", "a5>", "b1<", "b5<"].into_iter().zip(Seat::ALL) {
+            assert_eq!(game.seat(), seat);
+            game.play(m.parse().unwrap()).unwrap();
+        }
+        assert_eq!(game.state().to_string(), "x3X/5/5/5/o3O X1");
+    }
+
+    #[test]
+    fn test_seats_take_own_or_neutral_tiles() {
+        let s = team_state("Xx3/5/5/5/oO3 X1");
+        assert!(s.validate("a1v".parse().unwrap()).is_ok());
+        assert!(s.validate("e1<".parse().unwrap()).is_ok());
+        // the tile of the partner and those of the other team are not movable
+        assert_eq!(s.validate("b1v".parse().unwrap()), Err(GameError::OpponentTile));
+        assert_eq!(s.validate("a5^".parse().unwrap()), Err(GameError::OpponentTile));
+        let s = team_state("Xx3/5/5/5/oO3 X2");
+        assert!(s.validate("b1v".parse().unwrap()).is_ok());
+        assert_eq!(s.validate("a1v".parse().unwrap()), Err(GameError::OpponentTile));
+        let (moves, n) = s.available_moves();
+        assert!(moves[..n].iter().all(|&m| s.validate(m).is_ok()));
+        assert_eq!(n, 44 - 2 - 2 - 3);
+    }
+
+    #[test]
+    fn test_bitboard_matches_board() {
+        let mut s: TeamState = TeamState::new([[None; 5]; 5], Seat::X1);
+        for _ in 0..100 {
+            let bb = TeamBitboard::from(s.board());
+            let (moves, n) = s.available_moves();
+            for &m in &moves[..n] {
+                let next = s.play(m).unwrap();
+                assert_eq!(bb.apply_unchecked(m, s.seat()), TeamBitboard::from(next.board()));
+                assert_eq!(next, TeamState::new(*next.board(), next.seat()));
+            }
+            let (unique, n_unique) = s.unique_moves();
+            assert!(n_unique <= n && unique[..n_unique].iter().all(|m| moves[..n].contains(m)));
+            if n == 0 {
+                // the seat has no tile of its own on the border and no neutral one
+                s = TeamState::new([[None; 5]; 5], Seat::X1);
+                continue;
+            }
+            s = s.play(moves[rand::rng().random_range(0..n)]).unwrap();
+            if s.winner(Rules::default()).is_some() {
+                s = TeamState::new([[None; 5]; 5], Seat::X1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_team_lines() {
+        // the tiles of both seats of a team make a line
+        let mut game = TeamGame::with_rules(team_state("XxXx1/5/5/5/oOoO1 X1"), Rules::default());
+        assert_eq!(game.status(), GameStatus::Ongoing);
+        assert_eq!(game.play("e1<".parse().unwrap()), Ok(GameStatus::Won(Player::X)));
+        assert_eq!(game.state().to_string(), "XXxXx/5/5/5/oOoO1 O1");
+        game.undo();
+        assert_eq!(game.seat(), Seat::X1);
+        // completing a line of the other team loses
+        let s = team_state("XoOoO/O4/5/5/5 X1");
+        let next = s.play("a1v".parse().unwrap()).unwrap();
+        assert_eq!(next.to_string(), "OoOoO/5/5/5/X4 O1");
+        assert_eq!(next.winner(Rules::default()), Some(Player::O));
+    }
+
+    #[test]
+    fn test_symmetric_key() {
+        let s = team_state("Xx3/5/5/5/oO3 O2");
+        for sym in Symmetry::ALL {
+            assert_eq!(s.transform(sym).symmetric_key(), s.symmetric_key());
+        }
+        assert_ne!(team_state("Xx3/5/5/5/oO3 O1").symmetric_key(), s.symmetric_key());
+        assert_ne!(team_state("xX3/5/5/5/oO3 O2").symmetric_key(), s.symmetric_key());
+    }
+
+    #[test]
+    fn test_mcts_team_game() {
+        // X1 completes the top row with the tile of X2
+        let root = team_state("XxXx1/O4/o4/O4/5 X1");
+        let m = mcts(root, 100, Rules::default(), MctsConfig::default(), None, None).unwrap();
+        assert_eq!(root.play(m).unwrap().winner(Rules::default()), Some(Player::X));
+        let result = root.rollouts(200, Rules::default());
+        assert_eq!(result.wins_x + result.wins_o + result.draws, 200);
+    }
+}
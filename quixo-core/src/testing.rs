@@ -0,0 +1,25 @@
+//! Fixtures shared by the unit tests of the modules.
+
+use crate::mcts::{GameState, MctsConfig};
+use crate::team::TeamState;
+
+/// A midgame position where X wins by playing `b5<`.
+pub const MIDGAME: &str = "XXX2/OX3/1X3/OX3/X1O2 X";
+/// `MIDGAME` after X played `b5<`, completing the first row.
+pub const MIDGAME_WON: &str = "XXX2/OX3/1X3/OX3/XXO2 O";
+
+/// The two-player state of the position string s.
+pub fn state(s: &str) -> GameState {
+    s.parse().unwrap()
+}
+
+/// The four-player state of the position string s.
+pub fn team_state(s: &str) -> TeamState {
+    s.parse().unwrap()
+}
+
+/// The default configuration with the given rollouts per leaf and sharing
+/// of symmetric states.
+pub fn config(rollouts_per_leaf: u32, symmetric: bool) -> MctsConfig {
+    MctsConfig { rollouts_per_leaf, symmetric, ..MctsConfig::default() }
+}
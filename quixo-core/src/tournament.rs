@@ -0,0 +1,256 @@
+//! Round-robin tournaments between agents, to measure their strength.
+//!
+//! Every pair of entrants plays the same number of games, each of them
+//! moving first in half of the games. Games are played in parallel, each
+//! with new agents made by the factories of the entrants.
+
+use std::fmt::Display;
+use rayon::prelude::*;
+use crate::agent::Agent;
+use crate::game::{Game, GameStatus, Player, Rules};
+use crate::mcts::GameState;
+
+/// Makes a new agent for each game.
+type Factory<const N: usize> = Box<dyn Fn() -> Box<dyn Agent<GameState<N>>> + Send + Sync>;
+
+/// Wins, draws and losses of a player, against one opponent or all of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Record {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Record {
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// Average score of the games, a win scoring 1 and a draw 1/2.
+    pub fn score(&self) -> f64 {
+        (self.wins as f64 + self.draws as f64 / 2.0) / self.games() as f64
+    }
+
+    /// The record of the opponent.
+    pub fn reversed(&self) -> Record {
+        Record { wins: self.losses, draws: self.draws, losses: self.wins }
+    }
+
+    /// Elo difference with the opponent implied by the score, with the
+    /// half-width of its 95% confidence interval.
+    /// A score of 0 or 1 is counted as half a game less extreme, so that
+    /// the estimate stays finite.
+    pub fn elo(&self) -> Elo {
+        let n = self.games() as f64;
+        if n == 0.0 {
+            return Elo { rating: 0.0, error: f64::INFINITY };
+        }
+        let clamp = |s: f64| s.clamp(0.5 / n, 1.0 - 0.5 / n);
+        let mean = clamp(self.score());
+        let deviation = (self.wins as f64 * (1.0 - mean).powi(2)
+            + self.draws as f64 * (0.5 - mean).powi(2)
+            + self.losses as f64 * mean.powi(2)) / n;
+        let margin = 1.96 * (deviation / n).sqrt();
+        let (low, high) = (elo_difference(clamp(mean - margin)), elo_difference(clamp(mean + margin)));
+        Elo { rating: elo_difference(mean), error: (high - low) / 2.0 }
+    }
+}
+
+impl std::ops::Add for Record {
+    type Output = Record;
+
+    fn add(self, other: Record) -> Record {
+        Record {
+            wins: self.wins + other.wins,
+            draws: self.draws + other.draws,
+            losses: self.losses + other.losses,
+        }
+    }
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.wins, self.draws, self.losses)
+    }
+}
+
+/// Elo difference expected between two players when one of them scores s
+/// on average against the other.
+fn elo_difference(s: f64) -> f64 {
+    -400.0 * (1.0 / s - 1.0).log10()
+}
+
+/// An Elo rating with the half-width of its 95% confidence interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Elo {
+    pub rating: f64,
+    pub error: f64,
+}
+
+impl Display for Elo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:+.0} ± {:.0}", self.rating, self.error)
+    }
+}
+
+/// The agents taking part in a tournament, and how many games each pair
+/// plays.
+pub struct Tournament<const N: usize = 5> {
+    entrants: Vec<(String, Factory<N>)>,
+    /// Games played by each pair of entrants, rounded up to an even number
+    /// so that both move first as often.
+    pub games_per_pair: u32,
+    pub rules: Rules,
+}
+
+impl<const N: usize> Tournament<N> {
+    pub fn new(games_per_pair: u32, rules: Rules) -> Self {
+        Tournament { entrants: Vec::new(), games_per_pair, rules }
+    }
+
+    /// Add an entrant, `make` returning a new agent for each game. The
+    /// entrant is named after the agents.
+    pub fn add<A: Agent<GameState<N>> + 'static>(&mut self, make: impl Fn() -> A + Send + Sync + 'static) -> &mut Self {
+        let name = make().name();
+        self.add_named(name, make)
+    }
+
+    /// Add an entrant with the given name, e.g. to tell apart agents of the
+    /// same kind.
+    pub fn add_named<A: Agent<GameState<N>> + 'static>(&mut self, name: impl Into<String>, make: impl Fn() -> A + Send + Sync + 'static) -> &mut Self {
+        self.entrants.push((name.into(), Box::new(move || Box::new(make()))));
+        self
+    }
+
+    /// Play all the games, in parallel.
+    pub fn run(&self) -> Standings {
+        let n = self.entrants.len();
+        let rounds = self.games_per_pair.div_ceil(2);
+        let games: Vec<(usize, usize)> = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .flat_map(|pair| (0..rounds).map(move |_| pair))
+            .collect();
+        // results of the games where the first entrant of the pair plays X
+        let results: Vec<(usize, usize, Option<Player>)> = games.into_par_iter()
+            .map(|(i, j)| (i, j, self.play(i, j)))
+            .collect();
+        let mut records = vec![vec![Record::default(); n]; n];
+        for (i, j, winner) in results {
+            let record = match winner {
+                Some(Player::X) => Record { wins: 1, draws: 0, losses: 0 },
+                Some(Player::O) => Record { wins: 0, draws: 0, losses: 1 },
+                None => Record { wins: 0, draws: 1, losses: 0 },
+            };
+            records[i][j] = records[i][j] + record;
+            records[j][i] = records[j][i] + record.reversed();
+        }
+        Standings { names: self.entrants.iter().map(|(name, _)| name.clone()).collect(), records }
+    }
+
+    /// Play one game from the empty board, entrant x moving first, and
+    /// return its winner.
+    fn play(&self, x: usize, o: usize) -> Option<Player> {
+        let mut agents = [(self.entrants[x].1)(), (self.entrants[o].1)()];
+        let mut game = Game::<N>::with_rules([[None; N]; N], Player::X, self.rules);
+        while game.status() == GameStatus::Ongoing {
+            let agent = &mut agents[game.player() as usize];
+            let m = agent.choose_move(&GameState::from(&game), &game.history(), self.rules)?;
+            game.play(m).expect("agents play legal moves");
+        }
+        game.winner()
+    }
+}
+
+/// Results of a tournament.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Standings {
+    pub names: Vec<String>,
+    /// Record of each entrant against each other entrant, in the order of
+    /// `names`.
+    pub records: Vec<Vec<Record>>,
+}
+
+impl Standings {
+    /// Record of entrant i against all the others.
+    pub fn total(&self, i: usize) -> Record {
+        self.records[i].iter().fold(Record::default(), |total, &r| total + r)
+    }
+
+    /// Elo rating of entrant i relative to the average of its opponents.
+    pub fn elo(&self, i: usize) -> Elo {
+        self.total(i).elo()
+    }
+}
+
+/// Formats the table of the records, W/D/L from the point of view of the
+/// entrant of the row, followed by totals and Elo ratings.
+impl Display for Standings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.names.iter().map(|name| name.len()).max().unwrap_or(0).max(11);
+        write!(f, "{:width$}", "")?;
+        for name in &self.names {
+            write!(f, " {:>width$}", name)?;
+        }
+        writeln!(f, " {:>width$} {:>width$}", "total", "elo")?;
+        for (i, name) in self.names.iter().enumerate() {
+            write!(f, "{:width$}", name)?;
+            for (j, record) in self.records[i].iter().enumerate() {
+                let cell = if i == j { String::from("-") } else { record.to_string() };
+                write!(f, " {:>width$}", cell)?;
+            }
+            writeln!(f, " {:>width$} {:>width$}", self.total(i).to_string(), self.elo(i).to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{MctsAgent, NegamaxAgent, RandomAgent};
+    use crate::mcts::MctsConfig;
+    use crate::negamax::SearchLimits;
+
+    #[test]
+    fn test_elo() {
+        let even = Record { wins: 10, draws: 5, losses: 10 };
+        assert_eq!(even.elo().rating, 0.0);
+        let elo = Record { wins: 75, draws: 0, losses: 25 }.elo();
+        assert!((elo.rating - 190.85).abs() < 0.01);
+        assert!(elo.error > 50.0 && elo.error < 100.0);
+        // more games, smaller error bars
+        assert!(Record { wins: 750, draws: 0, losses: 250 }.elo().error < elo.error / 3.0);
+        assert!(Record { wins: 10, draws: 0, losses: 0 }.elo().rating.is_finite());
+        assert_eq!(Record { wins: 1, draws: 2, losses: 3 }.reversed().elo().rating, -Record { wins: 1, draws: 2, losses: 3 }.elo().rating);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let rules = Rules::default();
+        let mut tournament: Tournament = Tournament::new(5, rules);
+        tournament
+            .add(|| RandomAgent)
+            .add(move || NegamaxAgent::new(SearchLimits::depth(1), rules))
+            .add_named("mcts", || MctsAgent::new(100, MctsConfig { rollouts_per_leaf: 1, ..MctsConfig::default() }));
+        let standings = tournament.run();
+        assert_eq!(standings.names, ["random", "negamax-d1", "mcts"]);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert_eq!(standings.records[i][j], standings.records[j][i].reversed());
+                // 3 games with each entrant moving first
+                assert_eq!(standings.records[i][j].games(), if i == j { 0 } else { 6 });
+            }
+            assert_eq!(standings.total(i).games(), 12);
+        }
+        // the search engines beat the random player
+        assert!(standings.records[1][0].score() > 0.5);
+        assert!(standings.records[2][0].score() > 0.5);
+        assert!(standings.elo(0).rating < 0.0);
+        let table = standings.to_string();
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.lines().nth(1).unwrap().starts_with("random"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Zobrist keys of game states.
+//!
+//! The key of a state is the xor of a random number for each tile on the
+//! board, depending on its cell and owner, and of `SIDE` when O is to move.
+//! A move only changes the cells of one row or column, so the key of the
+//! new state is computed by updating those cells alone, and so are the keys
+//! of the boards symmetric to it.
+
+use std::hash::{BuildHasherDefault, Hasher};
+use crate::game::{Board, Move, Player, Shift, MAX_SIZE};
+use crate::symmetry::Symmetry;
+use crate::team::{Seat, SeatBoard};
+
+/// splitmix64, to fill the tables at compile time.
+const fn next_random(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (state, z ^ (z >> 31))
+}
+
+/// K random numbers for each cell, indexed by `MAX_SIZE * y + x` whatever
+/// the size of the board.
+const fn random_cells<const K: usize>(seed: u64) -> [[u64; K]; MAX_SIZE * MAX_SIZE] {
+    let mut cells = [[0; K]; MAX_SIZE * MAX_SIZE];
+    let mut state = seed;
+    let mut i = 0;
+    while i < MAX_SIZE * MAX_SIZE {
+        let mut k = 0;
+        while k < K {
+            let (s, r) = next_random(state);
+            cells[i][k] = r;
+            state = s;
+            k += 1;
+        }
+        i += 1;
+    }
+    cells
+}
+
+/// Random numbers for a tile of X and of O in each cell.
+const CELLS: [[u64; 2]; MAX_SIZE * MAX_SIZE] = random_cells(0x5158_4f58_4f51_5849);
+
+/// Random numbers for a tile of each seat of the four-player game in each
+/// cell, in `Seat::ALL` order.
+const SEAT_CELLS: [[u64; 4]; MAX_SIZE * MAX_SIZE] = random_cells(0x5345_4154_5351_5849);
+
+/// Random numbers marking the seat to move, none for the first seat.
+const SEAT_SIDES: [u64; 4] = {
+    let (s, o1) = next_random(0x5349_4445_5351_5849);
+    let (s, x2) = next_random(s);
+    let (_, o2) = next_random(s);
+    [0, o1, x2, o2]
+};
+
+/// Random number marking O as the player to move.
+pub const SIDE: u64 = next_random(0x4f5f544f5f4d4f56).1;
+
+/// Random number of the cell in column x and row y holding the given tile.
+pub fn cell_key(x: usize, y: usize, cell: Option<Player>) -> u64 {
+    match cell {
+        Some(Player::X) => CELLS[MAX_SIZE * y + x][0],
+        Some(Player::O) => CELLS[MAX_SIZE * y + x][1],
+        None => 0,
+    }
+}
+
+fn side_key(p: Player) -> u64 {
+    match p {
+        Player::X => 0,
+        Player::O => SIDE,
+    }
+}
+
+/// Key of the board b with player p to move, computed from scratch.
+pub fn key<const N: usize>(b: &Board<N>, p: Player) -> u64 {
+    let mut key = side_key(p);
+    for (y, row) in b.iter().enumerate() {
+        for (x, &cell) in row.iter().enumerate() {
+            key ^= cell_key(x, y, cell);
+        }
+    }
+    key
+}
+
+/// Key of the board b of the four-player game with seat s to move, computed
+/// from scratch.
+pub fn seat_key<const N: usize>(b: &SeatBoard<N>, s: Seat) -> u64 {
+    let mut key = SEAT_SIDES[s.index()];
+    for (y, row) in b.iter().enumerate() {
+        for (x, &cell) in row.iter().enumerate() {
+            key ^= seat_cell_key(x, y, cell);
+        }
+    }
+    key
+}
+
+/// Random number of the cell in column x and row y of the four-player game
+/// holding the tile of the given seat.
+fn seat_cell_key(x: usize, y: usize, cell: Option<Seat>) -> u64 {
+    cell.map_or(0, |t| SEAT_CELLS[MAX_SIZE * y + x][t.index()])
+}
+
+/// Key after player p plays the valid move m on the board b, whose key is
+/// `key`. Only the cells between the picked tile and the destination edge
+/// are updated.
+pub fn update<const N: usize>(key: u64, b: &Board<N>, m: Move, p: Player) -> u64 {
+    key ^ side_key(p) ^ side_key(p.next()) ^ moved_cells(b, m, Some(p), cell_key)
+}
+
+/// Keys of the 8 boards symmetric to the board b, in `Symmetry::ALL` order,
+/// with player p to move, computed from scratch. The first one is the key of
+/// b itself.
+pub fn symmetric_keys<const N: usize>(b: &Board<N>, p: Player) -> [u64; 8] {
+    Symmetry::ALL.map(|s| key(&s.apply_board(b), p))
+}
+
+/// `symmetric_keys` after player p plays the valid move m on the board b,
+/// updated like `update` does: each symmetric board changes in the image of
+/// the cells changed on b.
+pub fn update_symmetric<const N: usize>(keys: [u64; 8], b: &Board<N>, m: Move, p: Player) -> [u64; 8] {
+    update_images(keys, side_key(p) ^ side_key(p.next()), b, m, Some(p), cell_key)
+}
+
+/// Keys of the 8 boards of the four-player game symmetric to the board b,
+/// with seat s to move, like `symmetric_keys`.
+pub fn symmetric_seat_keys<const N: usize>(b: &SeatBoard<N>, s: Seat) -> [u64; 8] {
+    Symmetry::ALL.map(|t| seat_key(&t.apply_board(b), s))
+}
+
+/// `symmetric_seat_keys` after seat s plays the valid move m on the board b,
+/// like `update_symmetric`.
+pub fn update_seat_symmetric<const N: usize>(keys: [u64; 8], b: &SeatBoard<N>, m: Move, s: Seat) -> [u64; 8] {
+    update_images(keys, SEAT_SIDES[s.index()] ^ SEAT_SIDES[s.next().index()], b, m, Some(s), seat_cell_key)
+}
+
+/// The keys of the 8 boards symmetric to the board b after the valid move m
+/// puts `tile` on it, `side` being the xor of the numbers of the sides to
+/// move before and after m.
+fn update_images<T: Copy, const N: usize>(keys: [u64; 8], side: u64, b: &[[T; N]; N], m: Move, tile: T, cell_key: impl Fn(usize, usize, T) -> u64) -> [u64; 8] {
+    std::array::from_fn(|i| {
+        let s = Symmetry::ALL[i];
+        keys[i] ^ side ^ moved_cells(b, m, tile, |x, y, cell| {
+            let (sx, sy) = s.apply_point::<N>(x as u8, y as u8);
+            cell_key(sx as usize, sy as usize, cell)
+        })
+    })
+}
+
+/// Xor of the random numbers of the cells changed when the valid move m
+/// puts `tile` on the board b, before and after the move, `cell_key` giving
+/// the random number of a cell holding a tile.
+fn moved_cells<T: Copy, const N: usize>(b: &[[T; N]; N], m: Move, tile: T, cell_key: impl Fn(usize, usize, T) -> u64) -> u64 {
+    let (x, y, last) = (m.x as usize, m.y as usize, N - 1);
+    let mut key = 0;
+    // each cell of the segment takes the tile of its neighbour on the side
+    // of the destination edge, the destination takes the new tile
+    let mut replace = |cx: usize, cy: usize, new: T| {
+        key ^= cell_key(cx, cy, b[cy][cx]) ^ cell_key(cx, cy, new);
+    };
+    match m.shift {
+        Shift::LEFT => {
+            for i in 1..=x {
+                replace(i, y, b[y][i - 1]);
+            }
+            replace(0, y, tile);
+        },
+        Shift::RIGHT => {
+            for i in x..last {
+                replace(i, y, b[y][i + 1]);
+            }
+            replace(last, y, tile);
+        },
+        Shift::TOP => {
+            for i in 1..=y {
+                replace(x, i, b[i - 1][x]);
+            }
+            replace(x, 0, tile);
+        },
+        Shift::BOTTOM => {
+            for i in y..last {
+                replace(x, i, b[i + 1][x]);
+            }
+            replace(x, last, tile);
+        },
+    }
+    key
+}
+
+/// Hasher for maps keyed by Zobrist keys, which are already uniformly
+/// distributed: `write_u64` keeps the key as it is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ZobristHasher(u64);
+
+impl Hasher for ZobristHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = self.0.rotate_left(8) ^ b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = i;
+    }
+}
+
+pub type BuildZobristHasher = BuildHasherDefault<ZobristHasher>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{allowed_moves, num_allowed_moves, random_move};
+
+    fn check_update_matches_key<const N: usize>(games: usize) {
+        for _ in 0..games {
+            let mut b: Board<N> = [[None; N]; N];
+            let mut p = Player::X;
+            let mut k = key(&b, p);
+            let mut keys = symmetric_keys(&b, p);
+            for _ in 0..40 {
+                for m in allowed_moves::<N>().into_iter().take(num_allowed_moves(N)) {
+                    if let Ok(new_board) = m.apply(p, &b) {
+                        assert_eq!(update(k, &b, m, p), key(&new_board, p.next()));
+                    }
+                }
+                let m = random_move(&b, p).unwrap();
+                k = update(k, &b, m, p);
+                keys = update_symmetric(keys, &b, m, p);
+                b = m.apply(p, &b).unwrap();
+                p = p.next();
+                assert_eq!(k, key(&b, p));
+                assert_eq!(keys, symmetric_keys(&b, p));
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_matches_key() {
+        check_update_matches_key::<5>(20);
+        check_update_matches_key::<3>(5);
+        check_update_matches_key::<6>(5);
+    }
+
+    #[test]
+    fn test_keys_differ() {
+        let b: Board = [[None; 5]; 5];
+        assert_eq!(key(&b, Player::X), 0);
+        assert_eq!(key(&b, Player::O), SIDE);
+        let mut all: Vec<u64> = CELLS.iter().flatten().copied().collect();
+        all.push(SIDE);
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 2 * MAX_SIZE * MAX_SIZE + 1);
+    }
+}
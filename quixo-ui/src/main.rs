@@ -1,8 +1,11 @@
 use color_eyre::Result;
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use quixo_core::{
-    game::{Board, Move, Player, Shift, winner},
-    mcts::{GameState, mcts},
+    agent::{Agent, MctsAgent},
+    cancel::CancelToken,
+    game::{Game, GameError, GameStatus, History, Move, Player, Rules, Shift},
+    mcts::{GameState, MctsConfig, SearchState},
+    team::{Seat, TeamGame, TeamState},
 };
 use ratatui::{
     DefaultTerminal, Frame,
@@ -15,11 +18,14 @@ use ratatui::{
     widgets::{Cell, Gauge, Paragraph, Row, Table},
 };
 use std::{
-    sync::mpsc,
+    sync::{Arc, Mutex, mpsc},
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/// Thinking time of the computer for each move.
+const SEARCH_TIME: Duration = Duration::from_secs(3);
+
 fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
     let terminal = ratatui::init();
@@ -28,38 +34,183 @@ fn main() -> color_eyre::Result<()> {
     result
 }
 
+/// A two-player game, or a four-player team game.
+#[derive(Debug)]
+pub enum Match {
+    Two(Game),
+    Four(TeamGame),
+}
+
+impl Match {
+    /// A new game of the other kind.
+    fn toggled(&self) -> Match {
+        match self {
+            Match::Two(_) => Match::Four(TeamGame::new()),
+            Match::Four(_) => Match::Two(Game::new()),
+        }
+    }
+
+    /// Tiles of the board, as displayed.
+    fn cells(&self) -> [[Text<'static>; 5]; 5] {
+        match self {
+            Match::Two(game) => game.board().map(|row| {
+                row.map(|cell| match cell {
+                    Some(Player::X) => Text::from("X").bold().blue(),
+                    Some(Player::O) => Text::from("O").bold().red(),
+                    None => Text::from("-"),
+                })
+            }),
+            Match::Four(game) => game.board().map(|row| {
+                row.map(|cell| match cell {
+                    Some(Seat::X1) => Text::from("X").bold().blue(),
+                    Some(Seat::X2) => Text::from("x").bold().blue(),
+                    Some(Seat::O1) => Text::from("O").bold().red(),
+                    Some(Seat::O2) => Text::from("o").bold().red(),
+                    None => Text::from("-"),
+                })
+            }),
+        }
+    }
+
+    /// The player or seat to move.
+    fn turn(&self) -> String {
+        match self {
+            Match::Two(game) => game.player().to_string(),
+            Match::Four(game) => game.seat().to_string(),
+        }
+    }
+
+    fn moves(&self) -> &[Move] {
+        match self {
+            Match::Two(game) => game.moves(),
+            Match::Four(game) => game.moves(),
+        }
+    }
+
+    fn status(&self) -> GameStatus {
+        match self {
+            Match::Two(game) => game.status(),
+            Match::Four(game) => game.status(),
+        }
+    }
+
+    fn play(&mut self, m: Move) -> Result<GameStatus, GameError> {
+        match self {
+            Match::Two(game) => game.play(m),
+            Match::Four(game) => game.play(m),
+        }
+    }
+
+    fn undo(&mut self) {
+        match self {
+            Match::Two(game) => game.undo(),
+            Match::Four(game) => game.undo(),
+        };
+    }
+
+    fn redo(&mut self) {
+        match self {
+            Match::Two(game) => game.redo(),
+            Match::Four(game) => game.redo(),
+        };
+    }
+
+    /// Let the computer choose the move of the side to move in a new thread,
+    /// until it is done or the token is cancelled.
+    fn spawn_search(&self, computer: &Computer, tx: mpsc::Sender<(u32, Option<Move>)>, cancel: CancelToken) -> JoinHandle<Option<Move>> {
+        match self {
+            Match::Two(game) => spawn_agent(&computer.two, GameState::from(game), game.history(), game.rules(), tx, cancel),
+            Match::Four(game) => spawn_agent(&computer.four, TeamState::from(game), game.history(), game.rules(), tx, cancel),
+        }
+    }
+}
+
+/// The computer players of both kinds of games.
+pub struct Computer {
+    two: Arc<Mutex<dyn Agent<GameState>>>,
+    four: Arc<Mutex<dyn Agent<TeamState>>>,
+}
+
+impl Default for Computer {
+    fn default() -> Self {
+        let config = MctsConfig { rollouts_per_leaf: 1000, ..MctsConfig::default() };
+        Computer {
+            two: Arc::new(Mutex::new(MctsAgent::<GameState>::timed(SEARCH_TIME, config))),
+            four: Arc::new(Mutex::new(MctsAgent::<TeamState>::timed(SEARCH_TIME, config))),
+        }
+    }
+}
+
+impl std::fmt::Debug for Computer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Computer").finish_non_exhaustive()
+    }
+}
+
+fn spawn_agent<S: SearchState + 'static>(
+    agent: &Arc<Mutex<dyn Agent<S>>>,
+    state: S,
+    history: History,
+    rules: Rules,
+    tx: mpsc::Sender<(u32, Option<Move>)>,
+    cancel: CancelToken,
+) -> JoinHandle<Option<Move>> {
+    let agent = Arc::clone(agent);
+    thread::spawn(move || {
+        let mut agent = agent.lock().unwrap();
+        agent.set_progress(Some(tx));
+        agent.set_cancel(Some(cancel));
+        agent.choose_move(&state, &history, rules)
+    })
+}
+
 /// The main application which holds the state and logic of the application.
 #[derive(Debug)]
 pub struct App {
-    turn: Player,
-    board: Board,
+    game: Match,
+    computer: Computer,
     running: bool,
     selected_position: (usize, usize),
-    winner: Option<Player>,
     thread_handle: Option<JoinHandle<Option<Move>>>,
+    /// Stops the running search.
+    cancel: Option<CancelToken>,
     progress_channel: Option<mpsc::Receiver<(u32, Option<Move>)>>,
     progress_value: Option<u32>,
+    search_started: Option<Instant>,
+    /// Why the last move was rejected.
+    message: Option<String>,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl App {
     /// Construct a new instance of [`App`].
     pub fn new() -> Self {
         App {
-            turn: Player::X,
-            board: [[None; 5]; 5],
+            game: Match::Two(Game::new()),
+            computer: Computer::default(),
             running: false,
             selected_position: (0, 0),
-            winner: None,
             thread_handle: None,
+            cancel: None,
             progress_channel: None,
             progress_value: None,
+            search_started: None,
+            message: None,
         }
     }
 
     pub fn reset(&mut self) {
-        self.board = [[None; 5]; 5];
-        self.winner = None;
-        self.turn = Player::X;
+        self.abort_search();
+        self.game = match self.game {
+            Match::Two(_) => Match::Two(Game::new()),
+            Match::Four(_) => Match::Four(TeamGame::new()),
+        };
+        self.message = None;
     }
 
     /// Run the application's main loop.
@@ -79,8 +230,8 @@ impl App {
     /// - <https://docs.rs/ratatui/latest/ratatui/widgets/index.html>
     /// - <https://github.com/ratatui/ratatui/tree/main/ratatui-widgets/examples>
     fn render(&mut self, frame: &mut Frame) {
-        let layout = Layout::vertical([Length(5), Length(1), Length(1), Length(3)]);
-        let [table_area, status_area, progress_area, help_area] = layout.areas(frame.area());
+        let layout = Layout::vertical([Length(5), Length(1), Length(1), Length(1), Length(4)]);
+        let [table_area, status_area, message_area, progress_area, help_area] = layout.areas(frame.area());
         let [table_area] = Layout::horizontal([Length(19)])
             .flex(Flex::Center)
             .areas(table_area);
@@ -88,23 +239,29 @@ impl App {
             .flex(Flex::Center)
             .areas(progress_area);
         let status_line = Line::from(format!(
-            "Turn: {}, Winner: {}",
-            self.turn,
-            self.winner.map_or(String::from("-"), |p| p.to_string()),
+            "Turn: {}, Moves: {}, Last: {}, Status: {}",
+            self.game.turn(),
+            self.game.moves().len(),
+            self.game.moves().last().map_or(String::from("-"), |m| m.to_string()),
+            self.game.status(),
         ))
         .centered();
-        let gauge = if let Some(p) = self.progress_value {
-            Some(Gauge::default().percent((p / 10) as u16))
-        } else {
-            None
-        };
+        let gauge = self.search_started.map(|start| {
+            let percent = (100 * start.elapsed().as_millis() / SEARCH_TIME.as_millis()).min(100) as u16;
+            let label = format!("{} iterations", self.progress_value.unwrap_or(0));
+            Gauge::default().percent(percent).label(label)
+        });
         let help = Paragraph::new(vec![
             Line::from("left, right, top, bottom: move selection").centered(),
             Line::from("shift + left, right, top, bottom: move selected piece").centered(),
-            Line::from("c: call mcts, r: reset, q: quit").centered(),
+            Line::from("c: call mcts, u: undo, y: redo, r: reset, m: 2/4 players, q: quit").centered(),
+            Line::from("while mcts runs, n: play its best move now, a: abort").centered(),
         ]);
         self.render_table(frame, table_area);
         frame.render_widget(status_line, status_area);
+        if let Some(message) = &self.message {
+            frame.render_widget(Line::from(message.as_str()).centered().red(), message_area);
+        }
         frame.render_widget(help, help_area);
         if let Some(g) = gauge {
             frame.render_widget(g, progress_area);
@@ -113,15 +270,10 @@ impl App {
 
     pub fn render_table(&mut self, frame: &mut Frame, area: Rect) {
         let mut rows = Vec::new();
-        for (i, r) in self.board.iter().enumerate() {
+        for (i, r) in self.game.cells().into_iter().enumerate() {
             let mut row = Vec::new();
-            for (j, p) in r.iter().enumerate() {
-                let text = match p {
-                    Some(Player::X) => Text::from("X").bold().blue(),
-                    Some(Player::O) => Text::from("O").bold().red(),
-                    _ => Text::from("-"),
-                }
-                .centered();
+            for (j, text) in r.into_iter().enumerate() {
+                let text = text.centered();
                 if self.selected_position == (i, j) {
                     row.push(Cell::from(text).on_white());
                 } else {
@@ -160,13 +312,11 @@ impl App {
         if let Some(h) = &self.thread_handle
             && h.is_finished()
             && let Some(h) = self.thread_handle.take()
-            && let Some(m) = h.join().unwrap()
         {
-            self.progress_channel = None;
-            self.progress_value = None;
-            self.board = m.apply(self.turn, &self.board).unwrap();
-            self.turn = self.turn.next();
-            self.winner = winner(&self.board);
+            self.clear_search();
+            if let Some(m) = h.join().unwrap() {
+                let _ = self.game.play(m);
+            }
         }
 
         Ok(())
@@ -202,78 +352,75 @@ impl App {
                     _ => self.selected_position.1 + 1,
                 }
             }
-            (KeyModifiers::SHIFT, KeyCode::Left) => {
-                let m = Move {
-                    x: self.selected_position.1 as u8,
-                    y: self.selected_position.0 as u8,
-                    shift: Shift::LEFT,
-                };
-                if let None = self.thread_handle
-                    && let Ok(b) = m.apply(self.turn, &self.board)
-                {
-                    self.board = b;
-                    self.turn = self.turn.next();
-                    self.winner = winner(&b);
-                }
+            (KeyModifiers::SHIFT, KeyCode::Left) => self.play_selected(Shift::LEFT),
+            (KeyModifiers::SHIFT, KeyCode::Down) => self.play_selected(Shift::BOTTOM),
+            (KeyModifiers::SHIFT, KeyCode::Up) => self.play_selected(Shift::TOP),
+            (KeyModifiers::SHIFT, KeyCode::Right) => self.play_selected(Shift::RIGHT),
+            (_, KeyCode::Char('U') | KeyCode::Char('u')) if self.thread_handle.is_none() => {
+                self.game.undo();
             }
-            (KeyModifiers::SHIFT, KeyCode::Down) => {
-                let m = Move {
-                    x: self.selected_position.1 as u8,
-                    y: self.selected_position.0 as u8,
-                    shift: Shift::BOTTOM,
-                };
-                if let None = self.thread_handle
-                    && let Ok(b) = m.apply(self.turn, &self.board)
-                {
-                    self.board = b;
-                    self.turn = self.turn.next();
-                    self.winner = winner(&b);
-                }
-            }
-            (KeyModifiers::SHIFT, KeyCode::Up) => {
-                let m = Move {
-                    x: self.selected_position.1 as u8,
-                    y: self.selected_position.0 as u8,
-                    shift: Shift::TOP,
-                };
-                if let None = self.thread_handle
-                    && let Ok(b) = m.apply(self.turn, &self.board)
-                {
-                    self.board = b;
-                    self.turn = self.turn.next();
-                    self.winner = winner(&b);
-                }
-            }
-            (KeyModifiers::SHIFT, KeyCode::Right) => {
-                let m = Move {
-                    x: self.selected_position.1 as u8,
-                    y: self.selected_position.0 as u8,
-                    shift: Shift::RIGHT,
-                };
-                if let None = self.thread_handle
-                    && let Ok(b) = m.apply(self.turn, &self.board)
-                {
-                    self.board = b;
-                    self.turn = self.turn.next();
-                    self.winner = winner(&b);
-                }
+            (_, KeyCode::Char('Y') | KeyCode::Char('y')) if self.thread_handle.is_none() => {
+                self.game.redo();
             }
             (_, KeyCode::Char('R') | KeyCode::Char('r')) => {
                 self.reset();
             }
+            (_, KeyCode::Char('M') | KeyCode::Char('m')) if self.thread_handle.is_none() => {
+                self.game = self.game.toggled();
+                self.message = None;
+            }
             (_, KeyCode::Char('C') | KeyCode::Char('c')) => {
-                let gm = GameState {
-                    board: self.board,
-                    player: self.turn,
-                };
+                if self.game.status() != GameStatus::Ongoing || self.thread_handle.is_some() {
+                    return;
+                }
                 let (tx, rx) = mpsc::channel();
+                let cancel = CancelToken::new();
                 self.progress_channel = Some(rx);
-                self.thread_handle = Some(thread::spawn(move || mcts(gm, 1000, 1000, Some(tx))));
+                self.search_started = Some(Instant::now());
+                self.thread_handle = Some(self.game.spawn_search(&self.computer, tx, cancel.clone()));
+                self.cancel = Some(cancel);
+            }
+            (_, KeyCode::Char('N') | KeyCode::Char('n')) => {
+                // the search returns its best move so far, played when the
+                // thread is done
+                if let Some(cancel) = &self.cancel {
+                    cancel.cancel();
+                }
             }
+            (_, KeyCode::Char('A') | KeyCode::Char('a')) => self.abort_search(),
             _ => {}
         }
     }
 
+    /// Stop the running search, if any, without playing its move.
+    fn abort_search(&mut self) {
+        if let Some(cancel) = &self.cancel {
+            cancel.cancel();
+        }
+        // the thread stops on its own
+        self.thread_handle = None;
+        self.clear_search();
+    }
+
+    fn clear_search(&mut self) {
+        self.cancel = None;
+        self.progress_channel = None;
+        self.progress_value = None;
+        self.search_started = None;
+    }
+
+    /// Plays the selected tile in the direction of shift, if the move is valid.
+    fn play_selected(&mut self, shift: Shift) {
+        let m = Move {
+            x: self.selected_position.1 as u8,
+            y: self.selected_position.0 as u8,
+            shift,
+        };
+        if self.thread_handle.is_none() {
+            self.message = self.game.play(m).err().map(|e| format!("{}: {}", m, e));
+        }
+    }
+
     /// Set running to false to quit the application.
     fn quit(&mut self) {
         self.running = false;